@@ -0,0 +1,11 @@
+// Entrypoint of the processor.
+//
+// Frame pointers are forced on, so the prologue of `_start_rust` may push a
+// frame record before any Rust code has had the chance to set up a stack.
+// Point sp at the area below the kernel first.
+.section .text.boot
+.globl _start
+_start:
+	adr	x0, _start
+	mov	sp, x0
+	b	_start_rust
@@ -0,0 +1,280 @@
+//! Receives a kernel image over the serial console and boots it.
+//!
+//! The kernel asks for an image by sending `REQUEST` every second. The sender
+//! (`tools/src/bin/chainload.rs`) answers with a `Header` holding the size and
+//! CRC-32 of the image, which the kernel acknowledges with `OK` or rejects
+//! with `SIZE_ERROR`. The image follows and is acknowledged with `OK` or
+//! `CHECKSUM_ERROR`.
+//!
+//! The image is received into the heap, which starts above `.dmesg` and so is
+//! out of the way of `LOAD_ADDRESS`. `Image::boot` relocates the copy loop in
+//! `chainload.s` to the heap as well, turns the MMU and caches off and lets
+//! the relocated loop copy the image over the running kernel and jump to it.
+//! The new kernel starts in EL1 with interrupts masked.
+use crate::prelude::*;
+use crate::serial::{self, ConsoleTransport, Transport};
+use crate::timer::Duration;
+
+/// Where the firmware loads `kernel8.img` and the image is copied to.
+pub const LOAD_ADDRESS: usize = 0x8_0000;
+
+/// Largest image, it has to end below the kernel log kept by `dmesg`.
+pub const MAX_IMAGE_SIZE: usize = 0x40_0000 - LOAD_ADDRESS;
+
+/// Sent by the kernel while waiting for a header.
+pub const REQUEST: [u8; 3] = [0x03; 3];
+
+/// Starts a header.
+pub const HEADER_MAGIC: [u8; 4] = *b"SLMK";
+
+/// Magic, size and checksum, the numbers are little endian.
+pub const HEADER_SIZE: usize = 12;
+
+pub const OK: [u8; 2] = *b"OK";
+pub const SIZE_ERROR: [u8; 2] = *b"SE";
+pub const CHECKSUM_ERROR: [u8; 2] = *b"CE";
+
+/// Ctrl-C on the console stops waiting for a header.
+const ABORT: u8 = 0x03;
+
+/// How long the sender may pause in the middle of a transfer.
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub size: u32,
+    pub checksum: u32,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(&HEADER_MAGIC);
+        bytes[4..8].copy_from_slice(&self.size.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Returns `None` if `bytes` does not start with `HEADER_MAGIC`.
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Option<Header> {
+        if bytes[..4] != HEADER_MAGIC {
+            return None;
+        }
+
+        let mut size = [0; 4];
+        let mut checksum = [0; 4];
+        size.copy_from_slice(&bytes[4..8]);
+        checksum.copy_from_slice(&bytes[8..]);
+
+        Some(Header {
+            size: u32::from_le_bytes(size),
+            checksum: u32::from_le_bytes(checksum),
+        })
+    }
+}
+
+/// CRC-32 as used by zlib and Ethernet.
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn error(message: &str) -> SalmiakError {
+    SalmiakErrorKind::ChainloadError(message.to_owned()).into()
+}
+
+fn read_exact<T: Transport>(link: &mut T, buf: &mut [u8]) -> Result<(), SalmiakError> {
+    let len = buf.len();
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = link.read(BYTE_TIMEOUT).ok_or_else(|| {
+            SalmiakErrorKind::ChainloadError(format!("Timed out after {} of {} bytes", i, len))
+        })?;
+    }
+    Ok(())
+}
+
+fn receive_from<T: Transport>(link: &mut T) -> Result<Vec<u8>, SalmiakError> {
+    link.write(&REQUEST);
+
+    // anything before the magic is the sender starting up or keys pressed on
+    // the console
+    let mut matched = 0;
+    while matched < HEADER_MAGIC.len() {
+        match link.read(REQUEST_INTERVAL) {
+            None => link.write(&REQUEST),
+            Some(ABORT) => return Err(error("Aborted")),
+            Some(c) if c == HEADER_MAGIC[matched] => matched += 1,
+            Some(c) if c == HEADER_MAGIC[0] => matched = 1,
+            Some(_) => matched = 0,
+        }
+    }
+
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[..4].copy_from_slice(&HEADER_MAGIC);
+    read_exact(link, &mut bytes[4..])?;
+    let header = Header::from_bytes(&bytes).ok_or_else(|| error("Bad header"))?;
+
+    let size = header.size as usize;
+    if size == 0 || size > MAX_IMAGE_SIZE {
+        link.write(&SIZE_ERROR);
+        return Err(SalmiakErrorKind::ChainloadError(format!(
+            "Image of {} bytes does not fit in {} bytes",
+            size, MAX_IMAGE_SIZE
+        ))
+        .into());
+    }
+    link.write(&OK);
+
+    let mut data = Vec::new();
+    data.resize(size, 0);
+    read_exact(link, &mut data)?;
+
+    if checksum(&data) != header.checksum {
+        link.write(&CHECKSUM_ERROR);
+        return Err(error("Checksum mismatch"));
+    }
+    link.write(&OK);
+
+    Ok(data)
+}
+
+/// A received kernel image.
+pub struct Image {
+    data: Vec<u8>,
+}
+
+impl Image {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Copies the image to `LOAD_ADDRESS` and jumps to it.
+    #[cfg(target_arch = "aarch64")]
+    pub fn boot(self) -> ! {
+        use crate::cpu::{interrupt, irq};
+
+        extern "C" {
+            static chainload_copy: u32;
+            static chainload_copy_end: u32;
+            fn chainload_enter(copy: *const u32, dst: usize, src: *const u8, len: usize) -> !;
+        }
+
+        interrupt::disable();
+        irq::disable_all();
+        serial::flush();
+
+        unsafe {
+            let start = &chainload_copy as *const u32;
+            let words = (&chainload_copy_end as *const u32 as usize - start as usize) / 4;
+            let copy: Vec<u32> = core::slice::from_raw_parts(start, words).to_vec();
+
+            let src = self.data.as_ptr() as usize;
+            assert!(
+                src >= LOAD_ADDRESS + self.data.len()
+                    && copy.as_ptr() as usize >= LOAD_ADDRESS + self.data.len(),
+                "The image buffer overlaps the load address"
+            );
+
+            chainload_enter(
+                copy.as_ptr(),
+                LOAD_ADDRESS,
+                self.data.as_ptr(),
+                self.data.len(),
+            )
+        }
+    }
+}
+
+/// Asks for a kernel image on the serial console and waits until one has
+/// been received or ctrl-C is pressed.
+pub fn receive() -> Result<Image, SalmiakError> {
+    receive_from(&mut ConsoleTransport).map(|data| Image { data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    struct TestLink {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl TestLink {
+        fn new(input: &[u8]) -> Self {
+            TestLink {
+                input: input.iter().copied().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Transport for TestLink {
+        fn read(&mut self, _timeout: Duration) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            self.output.extend_from_slice(bytes);
+        }
+    }
+
+    fn transfer(image: &[u8], checksum: u32) -> Vec<u8> {
+        let header = Header {
+            size: image.len() as u32,
+            checksum,
+        };
+        let mut input = b"noise S".to_vec();
+        input.extend_from_slice(&header.to_bytes());
+        input.extend_from_slice(image);
+        input
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn receives_an_image() {
+        let image = b"\0\x01kernel\0";
+        let mut link = TestLink::new(&transfer(image, checksum(image)));
+
+        assert_eq!(receive_from(&mut link).unwrap(), image.to_vec());
+        assert_eq!(link.output, b"\x03\x03\x03OKOK".to_vec());
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let mut link = TestLink::new(&transfer(b"kernel", 0));
+        assert!(receive_from(&mut link).is_err());
+        assert!(link.output.ends_with(&CHECKSUM_ERROR));
+
+        let mut link = TestLink::new(&transfer(b"", 0));
+        assert!(receive_from(&mut link).is_err());
+        assert!(link.output.ends_with(&SIZE_ERROR));
+
+        // the sender stops half way
+        let mut input = transfer(b"kernel", checksum(b"kernel"));
+        input.truncate(input.len() - 2);
+        assert!(receive_from(&mut TestLink::new(&input)).is_err());
+    }
+}
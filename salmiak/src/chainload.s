@@ -0,0 +1,82 @@
+// Hands the machine to a kernel received by chainload.rs.
+//
+// chainload_enter(x0 = relocated chainload_copy, x1 = destination,
+//                 x2 = source, x3 = length)
+//
+// Runs from the kernel image. Turns the MMU and caches off, writes back and
+// invalidates every data cache level by set/way so that memory holds the
+// image and nothing stale is left for the new kernel, then continues in the
+// relocated copy loop.
+.section .text
+.globl chainload_enter
+chainload_enter:
+	msr	daifset, #0xf
+
+	mrs	x4, sctlr_el1
+	bic	x4, x4, #1		// M, MMU
+	bic	x4, x4, #(1 << 2)	// C, data cache
+	bic	x4, x4, #(1 << 12)	// I, instruction cache
+	msr	sctlr_el1, x4
+	isb
+
+	mrs	x4, clidr_el1
+	and	x5, x4, #0x07000000	// level of coherence
+	lsr	x5, x5, #23		// times two, like csselr
+	cbz	x5, 5f
+	mov	x6, #0			// level times two
+1:	add	x7, x6, x6, lsr #1	// level times three
+	lsr	x7, x4, x7
+	and	x7, x7, #7		// cache type of this level
+	cmp	x7, #2
+	b.lt	4f			// no data cache
+	msr	csselr_el1, x6
+	isb
+	mrs	x7, ccsidr_el1
+	and	x8, x7, #7
+	add	x8, x8, #4		// log2 of the line length
+	ubfx	x9, x7, #3, #10		// ways - 1
+	clz	w10, w9			// position of the way in dc cisw
+	ubfx	x11, x7, #13, #15	// sets - 1
+2:	mov	x12, x9
+3:	lsl	x13, x12, x10
+	orr	x13, x13, x6
+	lsl	x14, x11, x8
+	orr	x13, x13, x14
+	dc	cisw, x13
+	subs	x12, x12, #1
+	b.ge	3b
+	subs	x11, x11, #1
+	b.ge	2b
+4:	add	x6, x6, #2
+	cmp	x5, x6
+	b.gt	1b
+5:	msr	csselr_el1, xzr
+	dsb	sy
+	ic	iallu
+	tlbi	vmalle1
+	dsb	sy
+	isb
+	br	x0
+
+// Copied out of the kernel image by Image::boot and run with the MMU off, so
+// it has to be position independent. Copies the image and jumps to it.
+.globl chainload_copy
+.globl chainload_copy_end
+chainload_copy:
+	mov	x4, x1
+1:	cbz	x3, 2f
+	ldrb	w5, [x2], #1
+	strb	w5, [x4], #1
+	sub	x3, x3, #1
+	b	1b
+2:	dsb	sy
+	ic	iallu
+	dsb	sy
+	isb
+	mov	x4, x1
+	mov	x0, xzr
+	mov	x1, xzr
+	mov	x2, xzr
+	mov	x3, xzr
+	br	x4
+chainload_copy_end:
@@ -1,8 +1,53 @@
+pub mod backtrace;
+pub mod esr;
+pub mod exception;
+pub mod interrupt;
+pub mod irq;
+pub mod pmu;
+pub mod symbols;
+
+use self::esr::{Esr, Syndrome};
+pub use self::exception::{ExceptionFrame, FpState};
+use self::interrupt::{CriticalSection, Mutex};
+use crate::gdb;
+use crate::logger::{debug, info, warn};
+use crate::power;
 use crate::prelude::*;
+use crate::serial;
 use crate::timer;
+use core::cell::Cell;
+use cortex_a::{asm, regs::*};
+
+/// What to do after an SError has been reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// Return to the interrupted code.
+    Resume,
+    /// Reset the board through `power::reset`.
+    Reset,
+    /// Print the exception state and stop.
+    Halt,
+}
+
+/// Decides how to recover from an SError.
+pub type SErrorHook = fn(&Syndrome, &ExceptionFrame) -> Recovery;
+
+/// Handler for the interrupt routed to the FIQ vector.
+pub type FiqHandler = fn(&mut ExceptionFrame);
+
+static SERROR_HOOK: Mutex<Cell<Option<SErrorHook>>> = Mutex::new(Cell::new(None));
+static FIQ_HANDLER: Mutex<Cell<Option<FiqHandler>>> = Mutex::new(Cell::new(None));
+
+const FIQ_CONTROL: *mut u32 = (mem_constants::MMIO_BASE + 0xB20C) as *mut u32;
+const FIQ_ENABLE: u32 = 1 << 7;
 
 #[no_mangle]
-pub extern "C" fn print_unhandled_exception(tp: u32, esr: u32, elr: u32, far: u32) {
+pub extern "C" fn print_unhandled_exception(
+    tp: u32,
+    esr: u32,
+    far: u64,
+    frame: &mut ExceptionFrame,
+) {
     let type_ = match tp {
         0 => "exception",
         1 => "irq",
@@ -10,61 +55,159 @@ pub extern "C" fn print_unhandled_exception(tp: u32, esr: u32, elr: u32, far: u3
         _ => "error",
     };
 
-    let cause = match esr >> 26 {
-        0b00_0000 => "Unknown",
-        0b00_0001 => "Trapped WFI/WFE",
-        0b00_1110 => "Illegal execution",
-        0b01_0101 => "System call",
-        0b10_0000 => "Instruction abort, lower EL",
-        0b10_0001 => "Instruction abort, same EL",
-        0b10_0010 => "Instruction alignment fault",
-        0b10_0100 => "Data abort, lower EL",
-        0b10_0101 => "Data abort, same EL",
-        0b10_0110 => "Stack alignment fault",
-        0b10_1100 => "Floating point",
-        _ => "Unknown",
-    };
+    let syndrome = Syndrome { esr: Esr(esr), far };
+
+    sprintln!(
+        "Unhandled {}: {} (esr: 0x{:x}), elr (address): 0x{:x}\n{}",
+        type_,
+        syndrome,
+        esr,
+        frame.elr,
+        frame
+    );
+
+    #[cfg(target_arch = "aarch64")]
+    backtrace::print_exception(frame);
+
+    sprintln!("goodnight...");
+}
+
+/// Handles synchronous exceptions. Debug exceptions go to the GDB stub, all
+/// others are fatal except for the `svc` of `exception::time_round_trip`.
+#[no_mangle]
+pub extern "C" fn handle_sync(frame: &mut ExceptionFrame) {
+    let esr = Esr(ESR_EL1.get());
+    if esr.svc_immediate() == Some(exception::ROUND_TRIP_SVC) {
+        return;
+    }
+    if gdb::handle_exception(frame, esr) {
+        return;
+    }
+
+    print_unhandled_exception(0, esr.0, FAR_EL1.get(), frame);
+    halt();
+}
+
+fn halt() -> ! {
+    // keep the end of the log for the next boot, like the panic handler
+    crate::dmesg::flush();
+    loop {
+        asm::wfe();
+    }
+}
+
+/// Routes interrupt `source` to the FIQ vector and calls `handler` for it.
+///
+/// Sources 0-63 are the GPU interrupts and 64-71 the ARM basic interrupts
+/// (BCM2835 ARM Peripherals, section 7.5). Only one source can be routed to
+/// FIQ at a time, so this replaces any previous handler.
+pub fn set_fiq_handler(source: u8, handler: FiqHandler) {
+    interrupt::free(|cs| {
+        FIQ_HANDLER.borrow(cs).set(Some(handler));
+        unsafe {
+            FIQ_CONTROL.write_volatile(FIQ_ENABLE | u32::from(source & 0x7f));
+        }
+    });
+}
+
+/// Stops routing interrupts to the FIQ vector.
+pub fn clear_fiq_handler() {
+    interrupt::free(|cs| {
+        unsafe {
+            FIQ_CONTROL.write_volatile(0);
+        }
+        FIQ_HANDLER.borrow(cs).set(None);
+    });
+}
 
-    panic!(
-        "Unhandled {}, esr: 0x{:x} ({}), elr (address): 0x{:x}, far (address): 0x{:x}, goodnight...",
-        type_, esr, cause, elr, far
+/// Sets the hook that decides what happens after an SError. Without a hook
+/// SErrors halt the kernel.
+pub fn set_serror_hook(hook: SErrorHook) {
+    interrupt::free(|cs| SERROR_HOOK.borrow(cs).set(Some(hook)));
+}
+
+#[no_mangle]
+pub extern "C" fn handle_fiq(frame: &mut ExceptionFrame) {
+    // FIQs are masked while handling the exception
+    let cs = unsafe { CriticalSection::new() };
+    match FIQ_HANDLER.borrow(&cs).get() {
+        Some(handler) => handler(frame),
+        None => {
+            print_unhandled_exception(2, ESR_EL1.get(), FAR_EL1.get(), frame);
+            halt();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn handle_serror(frame: &mut ExceptionFrame) {
+    let syndrome = Syndrome {
+        esr: Esr(ESR_EL1.get()),
+        far: FAR_EL1.get(),
+    };
+    sprintln!(
+        "SError: {} (esr: 0x{:x}), elr (address): 0x{:x}",
+        syndrome,
+        syndrome.esr.0,
+        frame.elr
     );
+
+    let cs = unsafe { CriticalSection::new() };
+    let recovery = SERROR_HOOK
+        .borrow(&cs)
+        .get()
+        .map_or(Recovery::Halt, |hook| hook(&syndrome, frame));
+
+    match recovery {
+        Recovery::Resume => sprintln!("resuming after SError"),
+        Recovery::Reset => power::reset(),
+        Recovery::Halt => {
+            print_unhandled_exception(3, syndrome.esr.0, syndrome.far, frame);
+            halt();
+        }
+    }
 }
 
 /// # Safety
 ///
 /// This function is unsafe since it is called from C and calling C functions
 #[no_mangle]
-pub unsafe extern "C" fn handle_irq() {
-    disable_irq();
+pub unsafe extern "C" fn handle_irq(_frame: &mut ExceptionFrame) {
+    // IRQs are masked by the hardware when taking the exception and unmasked
+    // again by the eret in kernel_exit
+    let pending = irq::local_pending();
+    if pending & irq::LOCAL_TIMERS != 0 {
+        irq::count_timer();
+        timer::handle_timer_interrupt();
+    }
 
-    let pending_irq = (0x4000_0060 as *mut u32).read_volatile(); // TODO: What does this address point to
-    match pending_irq {
-        0x01..=0x8 => timer::handle_timer_interrupt(),
-        _ => sprintln!("unknown IRQ type: {}", pending_irq),
+    if pending & irq::LOCAL_GPU != 0 {
+        irq::dispatch_gpu();
     }
-    enable_irq();
-}
 
-extern "C" {
-    fn enable_irq();
-    fn disable_irq();
+    if pending & !(irq::LOCAL_TIMERS | irq::LOCAL_GPU) != 0 {
+        warn!("unknown IRQ type: {}", pending);
+    }
 }
 
 const INTERRUPT_CONTROLLER: *mut u32 = 0x4000_0040 as *mut u32;
 
 pub fn init() -> Result<(), SalmiakError> {
-    sprintln!("initializing cpu...");
+    info!("initializing cpu...");
 
-    sprintln!("* setting up timer irq");
+    debug!("setting up timer irq");
     timer::setup_timer_interrupt();
 
-    sprintln!("* enabling interrupts");
+    debug!("enabling interrupts");
     unsafe {
         INTERRUPT_CONTROLLER.write_volatile(0x2); // TODO: this should be nicer should have abstraction for interrupt controller
-        enable_irq();
+        interrupt::enable();
     }
+    interrupt::enable_serror();
+
+    debug!("enabling serial interrupts");
+    serial::enable_interrupts();
 
-    sprintln!("done!");
+    info!("cpu initialized");
     Ok(())
 }
@@ -0,0 +1,148 @@
+//! Frame pointer based stack unwinding.
+//!
+//! Every function keeps a frame record of `[previous fp, return address]`
+//! pointed to by x29 since frame pointers are forced on in `.cargo/config`.
+use super::symbols::SymbolTable;
+use super::ExceptionFrame;
+
+const MAX_FRAMES: usize = 32;
+
+/// Walks the frame record chain starting at `fp` and calls `f` with the
+/// address of each call site. Frame records have to be below `stack_top`.
+pub fn walk<F: FnMut(u64)>(mut fp: u64, stack_top: u64, mut f: F) {
+    for _ in 0..MAX_FRAMES {
+        if fp == 0 || fp % 16 != 0 || fp >= stack_top {
+            break;
+        }
+
+        let record = fp as *const u64;
+        let (next, lr) = unsafe { (record.read_volatile(), record.add(1).read_volatile()) };
+        // return addresses follow a 4 byte branch, this is a corrupt record
+        if lr < 4 {
+            break;
+        }
+
+        // the return address points past the branch instruction
+        f(lr - 4);
+
+        // the stack grows down, so the chain must move upwards
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn stack_top() -> u64 {
+    extern "C" {
+        fn _start();
+    }
+
+    _start as *const () as u64
+}
+
+#[cfg(target_arch = "aarch64")]
+fn frame_pointer() -> u64 {
+    let fp;
+    unsafe {
+        asm!("mov $0, x29" : "=r"(fp) ::: "volatile");
+    }
+    fp
+}
+
+fn print_address(index: usize, address: u64, symbols: Option<&SymbolTable>) {
+    match symbols.and_then(|s| s.lookup(address)) {
+        Some((symbol, offset)) => sprintln!(
+            "  #{:<2} 0x{:016x} {}+0x{:x}",
+            index,
+            address,
+            symbol.name,
+            offset
+        ),
+        None => sprintln!("  #{:<2} 0x{:016x} ?", index, address),
+    }
+}
+
+/// Prints the call stack of the caller.
+#[cfg(target_arch = "aarch64")]
+#[inline(never)]
+pub fn print() {
+    let symbols = SymbolTable::kernel();
+    let mut index = 0;
+
+    sprintln!("backtrace:");
+    walk(frame_pointer(), stack_top(), |address| {
+        print_address(index, address, symbols.as_ref());
+        index += 1;
+    });
+}
+
+/// Prints the call stack of the code that was interrupted by an exception.
+#[cfg(target_arch = "aarch64")]
+pub fn print_exception(frame: &ExceptionFrame) {
+    let symbols = SymbolTable::kernel();
+    let mut index = 1;
+
+    sprintln!("backtrace:");
+    print_address(0, frame.elr, symbols.as_ref());
+    walk(frame.frame_pointer(), stack_top(), |address| {
+        print_address(index, address, symbols.as_ref());
+        index += 1;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(16))]
+    struct Stack([u64; 16]);
+
+    #[test]
+    fn walk_frame_records() {
+        let mut stack = Stack([0; 16]);
+        let base = stack.0.as_ptr() as u64;
+        let top = base + 16 * 8;
+
+        // three records at slot 0, 4 and 8, the last one ends the chain
+        stack.0[0] = base + 4 * 8;
+        stack.0[1] = 0x8_1004;
+        stack.0[4] = base + 8 * 8;
+        stack.0[5] = 0x8_2008;
+        stack.0[8] = 0;
+        stack.0[9] = 0x8_300c;
+
+        let mut addresses = Vec::new();
+        walk(base, top, |a| addresses.push(a));
+
+        assert_eq!(addresses, vec![0x8_1000, 0x8_2004, 0x8_3008]);
+    }
+
+    #[test]
+    fn walk_stops_on_bad_records() {
+        let mut stack = Stack([0; 16]);
+        let base = stack.0.as_ptr() as u64;
+        let top = base + 16 * 8;
+
+        // points back down the stack
+        stack.0[4] = base;
+        stack.0[5] = 0x8_1004;
+
+        let mut addresses = Vec::new();
+        walk(base + 4 * 8, top, |a| addresses.push(a));
+        assert_eq!(addresses, vec![0x8_1000]);
+
+        // a return address that cannot follow a branch instruction
+        addresses.clear();
+        stack.0[4] = base + 8 * 8;
+        stack.0[5] = 2;
+        walk(base + 4 * 8, top, |a| addresses.push(a));
+        assert!(addresses.is_empty());
+
+        // misaligned and outside the stack
+        walk(base + 8, top, |a| addresses.push(a));
+        walk(top, top, |a| addresses.push(a));
+        assert!(addresses.is_empty());
+    }
+}
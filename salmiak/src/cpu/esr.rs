@@ -0,0 +1,486 @@
+use core::fmt::{self, Display, Formatter};
+
+/// Exception syndrome as read from ESR_EL1.
+///
+/// AArch64 Reference Manual, section D13.2.37
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Esr(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExceptionClass {
+    Unknown,
+    TrappedWfiWfe,
+    TrappedFp,
+    IllegalExecution,
+    Svc,
+    Hvc,
+    Smc,
+    TrappedMsrMrs,
+    InstructionAbortLowerEl,
+    InstructionAbortSameEl,
+    PcAlignment,
+    DataAbortLowerEl,
+    DataAbortSameEl,
+    SpAlignment,
+    TrappedFp64,
+    SError,
+    BreakpointLowerEl,
+    BreakpointSameEl,
+    SoftwareStepLowerEl,
+    SoftwareStepSameEl,
+    WatchpointLowerEl,
+    WatchpointSameEl,
+    Brk,
+    Other(u8),
+}
+
+impl From<u8> for ExceptionClass {
+    fn from(ec: u8) -> Self {
+        match ec {
+            0b00_0000 => ExceptionClass::Unknown,
+            0b00_0001 => ExceptionClass::TrappedWfiWfe,
+            0b00_0111 => ExceptionClass::TrappedFp,
+            0b00_1110 => ExceptionClass::IllegalExecution,
+            0b01_0101 => ExceptionClass::Svc,
+            0b01_0110 => ExceptionClass::Hvc,
+            0b01_0111 => ExceptionClass::Smc,
+            0b01_1000 => ExceptionClass::TrappedMsrMrs,
+            0b10_0000 => ExceptionClass::InstructionAbortLowerEl,
+            0b10_0001 => ExceptionClass::InstructionAbortSameEl,
+            0b10_0010 => ExceptionClass::PcAlignment,
+            0b10_0100 => ExceptionClass::DataAbortLowerEl,
+            0b10_0101 => ExceptionClass::DataAbortSameEl,
+            0b10_0110 => ExceptionClass::SpAlignment,
+            0b10_1100 => ExceptionClass::TrappedFp64,
+            0b10_1111 => ExceptionClass::SError,
+            0b11_0000 => ExceptionClass::BreakpointLowerEl,
+            0b11_0001 => ExceptionClass::BreakpointSameEl,
+            0b11_0010 => ExceptionClass::SoftwareStepLowerEl,
+            0b11_0011 => ExceptionClass::SoftwareStepSameEl,
+            0b11_0100 => ExceptionClass::WatchpointLowerEl,
+            0b11_0101 => ExceptionClass::WatchpointSameEl,
+            0b11_1100 => ExceptionClass::Brk,
+            ec => ExceptionClass::Other(ec),
+        }
+    }
+}
+
+impl Display for ExceptionClass {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let desc = match self {
+            ExceptionClass::Unknown => "Unknown reason",
+            ExceptionClass::TrappedWfiWfe => "Trapped WFI/WFE",
+            ExceptionClass::TrappedFp => "Trapped SIMD/floating point access",
+            ExceptionClass::IllegalExecution => "Illegal execution state",
+            ExceptionClass::Svc => "System call",
+            ExceptionClass::Hvc => "Hypervisor call",
+            ExceptionClass::Smc => "Secure monitor call",
+            ExceptionClass::TrappedMsrMrs => "Trapped MSR/MRS",
+            ExceptionClass::InstructionAbortLowerEl => "Instruction abort, lower EL",
+            ExceptionClass::InstructionAbortSameEl => "Instruction abort, same EL",
+            ExceptionClass::PcAlignment => "Instruction alignment fault",
+            ExceptionClass::DataAbortLowerEl => "Data abort, lower EL",
+            ExceptionClass::DataAbortSameEl => "Data abort, same EL",
+            ExceptionClass::SpAlignment => "Stack alignment fault",
+            ExceptionClass::TrappedFp64 => "Floating point",
+            ExceptionClass::SError => "SError interrupt",
+            ExceptionClass::BreakpointLowerEl => "Breakpoint, lower EL",
+            ExceptionClass::BreakpointSameEl => "Breakpoint, same EL",
+            ExceptionClass::SoftwareStepLowerEl => "Software step, lower EL",
+            ExceptionClass::SoftwareStepSameEl => "Software step, same EL",
+            ExceptionClass::WatchpointLowerEl => "Watchpoint, lower EL",
+            ExceptionClass::WatchpointSameEl => "Watchpoint, same EL",
+            ExceptionClass::Brk => "Breakpoint instruction",
+            ExceptionClass::Other(ec) => return write!(f, "Unknown exception class 0b{:06b}", ec),
+        };
+
+        write!(f, "{}", desc)
+    }
+}
+
+/// Decoded DFSC/IFSC field of a data or instruction abort.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultStatus {
+    AddressSize { level: u8 },
+    Translation { level: u8 },
+    AccessFlag { level: u8 },
+    Permission { level: u8 },
+    SynchronousExternal,
+    SynchronousExternalWalk { level: u8 },
+    Parity,
+    ParityWalk { level: u8 },
+    Alignment,
+    TlbConflict,
+    Lockdown,
+    UnsupportedExclusive,
+    Other(u8),
+}
+
+impl From<u8> for FaultStatus {
+    fn from(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc {
+            0b00_0000..=0b00_0011 => FaultStatus::AddressSize { level },
+            0b00_0100..=0b00_0111 => FaultStatus::Translation { level },
+            0b00_1001..=0b00_1011 => FaultStatus::AccessFlag { level },
+            0b00_1101..=0b00_1111 => FaultStatus::Permission { level },
+            0b01_0000 => FaultStatus::SynchronousExternal,
+            0b01_0100..=0b01_0111 => FaultStatus::SynchronousExternalWalk { level },
+            0b01_1000 => FaultStatus::Parity,
+            0b01_1100..=0b01_1111 => FaultStatus::ParityWalk { level },
+            0b10_0001 => FaultStatus::Alignment,
+            0b11_0000 => FaultStatus::TlbConflict,
+            0b11_0100 => FaultStatus::Lockdown,
+            0b11_0101 => FaultStatus::UnsupportedExclusive,
+            fsc => FaultStatus::Other(fsc),
+        }
+    }
+}
+
+impl Display for FaultStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FaultStatus::AddressSize { level } => write!(f, "address size fault, level {}", level),
+            FaultStatus::Translation { level } => write!(f, "translation fault, level {}", level),
+            FaultStatus::AccessFlag { level } => write!(f, "access flag fault, level {}", level),
+            FaultStatus::Permission { level } => write!(f, "permission fault, level {}", level),
+            FaultStatus::SynchronousExternal => write!(f, "synchronous external abort"),
+            FaultStatus::SynchronousExternalWalk { level } => write!(
+                f,
+                "synchronous external abort on table walk, level {}",
+                level
+            ),
+            FaultStatus::Parity => write!(f, "parity/ECC error"),
+            FaultStatus::ParityWalk { level } => {
+                write!(f, "parity/ECC error on table walk, level {}", level)
+            }
+            FaultStatus::Alignment => write!(f, "alignment fault"),
+            FaultStatus::TlbConflict => write!(f, "TLB conflict abort"),
+            FaultStatus::Lockdown => write!(f, "lockdown abort"),
+            FaultStatus::UnsupportedExclusive => write!(f, "unsupported exclusive access"),
+            FaultStatus::Other(fsc) => write!(f, "unknown fault status 0b{:06b}", fsc),
+        }
+    }
+}
+
+/// Error type (AET) of an asynchronous SError, from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorType {
+    Uncontainable,
+    Unrecoverable,
+    Restartable,
+    Recoverable,
+    Corrected,
+    Other(u8),
+}
+
+impl From<u8> for ErrorType {
+    fn from(aet: u8) -> Self {
+        match aet {
+            0b000 => ErrorType::Uncontainable,
+            0b001 => ErrorType::Unrecoverable,
+            0b010 => ErrorType::Restartable,
+            0b011 => ErrorType::Recoverable,
+            0b110 => ErrorType::Corrected,
+            aet => ErrorType::Other(aet),
+        }
+    }
+}
+
+impl Display for ErrorType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ErrorType::Uncontainable => write!(f, "uncontainable"),
+            ErrorType::Unrecoverable => write!(f, "unrecoverable"),
+            ErrorType::Restartable => write!(f, "restartable"),
+            ErrorType::Recoverable => write!(f, "recoverable"),
+            ErrorType::Corrected => write!(f, "corrected"),
+            ErrorType::Other(aet) => write!(f, "unknown error type 0b{:03b}", aet),
+        }
+    }
+}
+
+const DFSC_ASYNC_SERROR: u8 = 0b01_0001;
+
+impl Esr {
+    pub fn class(self) -> ExceptionClass {
+        ExceptionClass::from((self.0 >> 26) as u8)
+    }
+
+    /// Instruction specific syndrome.
+    pub fn iss(self) -> u32 {
+        self.0 & 0x01ff_ffff
+    }
+
+    /// True if the trapped instruction was 32 bits wide.
+    pub fn il(self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    fn is_data_abort(self) -> bool {
+        match self.class() {
+            ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortSameEl => true,
+            _ => false,
+        }
+    }
+
+    fn is_instruction_abort(self) -> bool {
+        match self.class() {
+            ExceptionClass::InstructionAbortLowerEl | ExceptionClass::InstructionAbortSameEl => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// DFSC or IFSC for data and instruction aborts.
+    pub fn fault_status(self) -> Option<FaultStatus> {
+        if self.is_data_abort() || self.is_instruction_abort() {
+            Some(FaultStatus::from((self.iss() & 0x3f) as u8))
+        } else {
+            None
+        }
+    }
+
+    /// WnR, true if a data abort was caused by a write.
+    pub fn is_write(self) -> Option<bool> {
+        if self.is_data_abort() {
+            Some(self.iss() & (1 << 6) != 0)
+        } else {
+            None
+        }
+    }
+
+    /// Size in bytes of the faulting access (SAS), only valid when ISV is set.
+    pub fn access_size(self) -> Option<u32> {
+        if self.is_data_abort() && self.iss() & (1 << 24) != 0 {
+            Some(1 << ((self.iss() >> 22) & 0b11))
+        } else {
+            None
+        }
+    }
+
+    /// False if FnV is set, meaning FAR_EL1 does not hold the faulting address.
+    pub fn far_valid(self) -> bool {
+        match self.class() {
+            ExceptionClass::PcAlignment
+            | ExceptionClass::WatchpointLowerEl
+            | ExceptionClass::WatchpointSameEl => true,
+            _ if self.is_data_abort() || self.is_instruction_abort() => self.iss() & (1 << 10) == 0,
+            _ => false,
+        }
+    }
+
+    /// Immediate given to the `svc` instruction.
+    pub fn svc_immediate(self) -> Option<u16> {
+        match self.class() {
+            ExceptionClass::Svc => Some(self.iss() as u16),
+            _ => None,
+        }
+    }
+
+    /// IDS, true if an SError has an implementation defined syndrome that
+    /// the other SError fields do not apply to.
+    pub fn serror_implementation_defined(self) -> Option<bool> {
+        match self.class() {
+            ExceptionClass::SError => Some(self.iss() & (1 << 24) != 0),
+            _ => None,
+        }
+    }
+
+    /// DFSC of an SError with an architected syndrome.
+    pub fn serror_fault_status(self) -> Option<u8> {
+        match self.serror_implementation_defined() {
+            Some(false) => Some((self.iss() & 0x3f) as u8),
+            _ => None,
+        }
+    }
+
+    /// AET, only reported for asynchronous SErrors.
+    pub fn serror_type(self) -> Option<ErrorType> {
+        match self.serror_fault_status() {
+            Some(DFSC_ASYNC_SERROR) => Some(ErrorType::from(((self.iss() >> 10) & 0b111) as u8)),
+            _ => None,
+        }
+    }
+
+    /// Comment given to the `brk` instruction.
+    pub fn brk_comment(self) -> Option<u16> {
+        match self.class() {
+            ExceptionClass::Brk => Some(self.iss() as u16),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Esr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.class())?;
+
+        if let Some(fault) = self.fault_status() {
+            write!(f, ": {}", fault)?;
+        }
+
+        if let Some(write) = self.is_write() {
+            write!(f, ", {}", if write { "write" } else { "read" })?;
+        }
+
+        if let Some(size) = self.access_size() {
+            write!(f, " of {} bytes", size)?;
+        }
+
+        if let Some(imm) = self.svc_immediate() {
+            write!(f, " #0x{:x}", imm)?;
+        }
+
+        if let Some(comment) = self.brk_comment() {
+            write!(f, " #0x{:x}", comment)?;
+        }
+
+        if let Some(true) = self.serror_implementation_defined() {
+            write!(
+                f,
+                ": implementation defined syndrome 0x{:x}",
+                self.iss() & 0x00ff_ffff
+            )?;
+        }
+
+        match (self.serror_fault_status(), self.serror_type()) {
+            (_, Some(aet)) => write!(f, ": asynchronous, {}", aet)?,
+            (Some(0), None) => write!(f, ": uncategorized")?,
+            (Some(dfsc), None) => write!(f, ": unknown fault status 0b{:06b}", dfsc)?,
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Exception syndrome together with the faulting address from FAR_EL1.
+#[derive(Debug, Clone, Copy)]
+pub struct Syndrome {
+    pub esr: Esr,
+    pub far: u64,
+}
+
+impl Display for Syndrome {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.esr)?;
+
+        if self.esr.far_valid() {
+            write!(f, " at FAR 0x{:x}", self.far)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_abort_permission_write() {
+        let esr = Esr(0x9600_004f);
+
+        assert_eq!(esr.class(), ExceptionClass::DataAbortSameEl);
+        assert_eq!(
+            esr.fault_status(),
+            Some(FaultStatus::Permission { level: 3 })
+        );
+        assert_eq!(esr.is_write(), Some(true));
+        assert!(esr.far_valid());
+        assert_eq!(
+            format!("{}", Syndrome { esr, far: 0x8_1000 }),
+            "Data abort, same EL: permission fault, level 3, write at FAR 0x81000"
+        );
+    }
+
+    #[test]
+    fn data_abort_syndrome_valid() {
+        // ISV set, SAS = word, read, translation fault level 2
+        let esr = Esr(0x9600_0000 | (1 << 24) | (0b10 << 22) | 0b00_0110);
+
+        assert_eq!(esr.access_size(), Some(4));
+        assert_eq!(esr.is_write(), Some(false));
+        assert_eq!(
+            format!("{}", esr),
+            "Data abort, same EL: translation fault, level 2, read of 4 bytes"
+        );
+    }
+
+    #[test]
+    fn far_not_valid() {
+        let esr = Esr(0x9600_0000 | (1 << 10) | 0b01_0000);
+
+        assert_eq!(esr.fault_status(), Some(FaultStatus::SynchronousExternal));
+        assert!(!esr.far_valid());
+        assert_eq!(
+            format!("{}", Syndrome { esr, far: 0x1234 }),
+            "Data abort, same EL: synchronous external abort, read"
+        );
+    }
+
+    #[test]
+    fn instruction_abort() {
+        let esr = Esr(0x8600_000d);
+
+        assert_eq!(esr.class(), ExceptionClass::InstructionAbortSameEl);
+        assert_eq!(
+            esr.fault_status(),
+            Some(FaultStatus::Permission { level: 1 })
+        );
+        assert_eq!(esr.is_write(), None);
+    }
+
+    #[test]
+    fn svc_and_brk() {
+        let svc = Esr(0x5600_0012);
+        let brk = Esr(0xf200_03e8);
+
+        assert_eq!(svc.svc_immediate(), Some(0x12));
+        assert_eq!(brk.brk_comment(), Some(1000));
+        assert_eq!(svc.fault_status(), None);
+        assert_eq!(format!("{}", brk), "Breakpoint instruction #0x3e8");
+    }
+
+    #[test]
+    fn serror() {
+        // asynchronous SError, AET = restartable
+        let esr = Esr(0xbe00_0000 | (0b010 << 10) | 0b01_0001);
+
+        assert_eq!(esr.class(), ExceptionClass::SError);
+        assert_eq!(esr.serror_implementation_defined(), Some(false));
+        assert_eq!(esr.serror_type(), Some(ErrorType::Restartable));
+        assert!(!esr.far_valid());
+        assert_eq!(
+            format!("{}", esr),
+            "SError interrupt: asynchronous, restartable"
+        );
+
+        let uncategorized = Esr(0xbe00_0000);
+        assert_eq!(uncategorized.serror_type(), None);
+        assert_eq!(
+            format!("{}", uncategorized),
+            "SError interrupt: uncategorized"
+        );
+
+        let implementation_defined = Esr(0xbf00_0002);
+        assert_eq!(implementation_defined.serror_fault_status(), None);
+        assert_eq!(
+            format!("{}", implementation_defined),
+            "SError interrupt: implementation defined syndrome 0x2"
+        );
+        assert_eq!(Esr(0x9600_0000).serror_type(), None);
+    }
+
+    #[test]
+    fn alignment_and_access_flag() {
+        assert_eq!(FaultStatus::from(0b10_0001), FaultStatus::Alignment);
+        assert_eq!(
+            FaultStatus::from(0b00_1010),
+            FaultStatus::AccessFlag { level: 2 }
+        );
+        assert_eq!(FaultStatus::from(0b11_1111), FaultStatus::Other(0b11_1111));
+    }
+}
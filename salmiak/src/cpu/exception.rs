@@ -0,0 +1,214 @@
+use crate::timer;
+use core::fmt::{self, Display, Formatter};
+use core::time::Duration;
+use cortex_a::regs::*;
+
+/// `svc` immediate that `handle_sync` returns from straight away, used by
+/// `time_round_trip`.
+pub const ROUND_TRIP_SVC: u16 = 0xff;
+
+/// Floating point and SIMD registers saved by `kernel_entry`.
+///
+/// These are saved on every exception rather than lazily on first use since
+/// the compiler is free to use SIMD registers for plain copies, so almost any
+/// handler touches them anyway. That is 16 paired 128 bit stores on entry and
+/// as many loads on exit, 1 KiB of extra stack traffic per exception.
+/// `time_round_trip` measures the time it adds to each exception.
+#[repr(C, align(16))]
+#[derive(Debug, Default)]
+pub struct FpState {
+    /// SIMD registers q0-q31.
+    pub q: [u128; 32],
+    /// Floating point control register.
+    pub fpcr: u64,
+    /// Floating point status register.
+    pub fpsr: u64,
+}
+
+/// Register state saved by `kernel_entry` in `exceptions.s`.
+///
+/// The layout has to match the offsets used by the `kernel_entry` and
+/// `kernel_exit` macros. Any register written through this struct is
+/// restored when the handler returns.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionFrame {
+    /// General purpose registers x0-x30.
+    pub regs: [u64; 31],
+    /// Exception link register, where execution continues on return.
+    pub elr: u64,
+    /// Saved program status.
+    pub spsr: u64,
+    /// Stack pointer at the time of the exception. This is informational only
+    /// and writes to it are ignored on return.
+    pub sp: u64,
+    /// Floating point state of the interrupted code.
+    pub fp: FpState,
+}
+
+impl ExceptionFrame {
+    /// Size of the frame in bytes, must match `FRAME_SIZE` in `exceptions.s`.
+    pub const SIZE: usize = 800;
+
+    pub fn frame_pointer(&self) -> u64 {
+        self.regs[29]
+    }
+
+    pub fn link_register(&self) -> u64 {
+        self.regs[30]
+    }
+}
+
+/// Time of an exception round trip, see `time_round_trip`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RoundTrip {
+    /// From an `svc` to the instruction after it, through `kernel_entry`,
+    /// `handle_sync` and `kernel_exit`.
+    pub total: Duration,
+    /// The part of `total` spent saving and restoring the `FpState`.
+    pub fp_state: Duration,
+}
+
+impl RoundTrip {
+    /// What the round trip would take without saving the `FpState`.
+    pub fn without_fp_state(&self) -> Duration {
+        self.total
+            .checked_sub(self.fp_state)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+extern "C" {
+    /// The `fp_save` and `fp_restore` of `kernel_entry` and `kernel_exit`.
+    fn fp_save_restore(state: *mut FpState);
+}
+
+/// Times an exception round trip, and saving and restoring the `FpState` on
+/// its own, with the system counter. The counter ticks much slower than the
+/// core, so both are averaged over `rounds` runs.
+#[cfg(target_arch = "aarch64")]
+pub fn time_round_trip(rounds: u32) -> RoundTrip {
+    let mut state = FpState::default();
+
+    RoundTrip {
+        // has to match ROUND_TRIP_SVC
+        total: average(rounds, || unsafe { asm!("svc #0xff" :::: "volatile") }),
+        fp_state: average(rounds, || unsafe { fp_save_restore(&mut state) }),
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn time_round_trip(_rounds: u32) -> RoundTrip {
+    RoundTrip::default()
+}
+
+fn average<F: FnMut()>(rounds: u32, mut f: F) -> Duration {
+    let start = timer::get_ticks();
+    for _ in 0..rounds {
+        f();
+    }
+    let ticks = timer::get_ticks().wrapping_sub(start);
+    per_round(ticks, u64::from(CNTFRQ_EL0.get()), rounds)
+}
+
+/// Time of one of `rounds` runs that took `ticks` at `frequency` Hz, zero if
+/// the frequency is not set up.
+fn per_round(ticks: u64, frequency: u64, rounds: u32) -> Duration {
+    let nanos = (u128::from(ticks) * 1_000_000_000)
+        .checked_div(u128::from(frequency) * u128::from(rounds))
+        .unwrap_or(0);
+    Duration::from_nanos(nanos as u64)
+}
+
+impl Display for ExceptionFrame {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, pair) in self.regs.chunks(2).enumerate() {
+            for (j, reg) in pair.iter().enumerate() {
+                let n = i * 2 + j;
+                write!(
+                    f,
+                    "{}x{:<2} 0x{:016x}",
+                    if j == 0 { "" } else { "  " },
+                    n,
+                    reg
+                )?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "sp   0x{:016x}", self.sp)?;
+        writeln!(f, "elr  0x{:016x}", self.elr)?;
+        write!(f, "spsr 0x{:016x}", self.spsr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    #[test]
+    fn layout_matches_kernel_entry() {
+        // kernel_entry stores x30/elr as a pair at 16 * 15 and spsr/sp at 16 * 16
+        let frame = ExceptionFrame {
+            regs: [0; 31],
+            elr: 0,
+            spsr: 0,
+            sp: 0,
+            fp: FpState::default(),
+        };
+        let base = &frame as *const _ as usize;
+
+        assert_eq!(&frame.regs[30] as *const _ as usize - base, 16 * 15);
+        assert_eq!(&frame.elr as *const _ as usize - base, 16 * 15 + 8);
+        assert_eq!(&frame.spsr as *const _ as usize - base, 16 * 16);
+        assert_eq!(&frame.sp as *const _ as usize - base, 16 * 16 + 8);
+
+        // FRAME_FP, FRAME_FPCR and FRAME_FPSR
+        assert_eq!(&frame.fp.q[0] as *const _ as usize - base, 16 * 17);
+        assert_eq!(&frame.fp.fpcr as *const _ as usize - base, 16 * 17 + 512);
+        assert_eq!(&frame.fp.fpsr as *const _ as usize - base, 16 * 17 + 520);
+        assert_eq!(size_of::<ExceptionFrame>(), ExceptionFrame::SIZE);
+    }
+
+    #[test]
+    fn register_dump() {
+        let mut frame = ExceptionFrame {
+            regs: [0; 31],
+            elr: 0x8_0000,
+            spsr: 0x3c5,
+            sp: 0x7_ff00,
+            fp: FpState::default(),
+        };
+        frame.regs[1] = 0xdead;
+
+        let dump = format!("{}", frame);
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(lines.len(), 16 + 3);
+        assert_eq!(lines[0], "x0  0x0000000000000000  x1  0x000000000000dead");
+        assert_eq!(lines[15], "x30 0x0000000000000000");
+        assert_eq!(lines[18], "spsr 0x00000000000003c5");
+    }
+
+    #[test]
+    fn round_trip_time() {
+        // 1000 round trips of 19 ticks at 19.2 MHz
+        assert_eq!(
+            per_round(19_000, 19_200_000, 1000),
+            Duration::from_nanos(989)
+        );
+        assert_eq!(per_round(19_000, 0, 1000), Duration::from_secs(0));
+        assert_eq!(per_round(19_000, 19_200_000, 0), Duration::from_secs(0));
+
+        let round_trip = RoundTrip {
+            total: Duration::from_nanos(300),
+            fp_state: Duration::from_nanos(120),
+        };
+        assert_eq!(round_trip.without_fp_state(), Duration::from_nanos(180));
+        assert_eq!(
+            RoundTrip::default().without_fp_state(),
+            Duration::from_secs(0)
+        );
+    }
+}
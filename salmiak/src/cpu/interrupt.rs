@@ -0,0 +1,150 @@
+//! Interrupt masking and critical sections.
+//!
+//! Modelled after `cortex_m::interrupt`: code that needs to run without
+//! being interrupted does so inside `free`, which hands out a
+//! `CriticalSection` token. APIs that are only safe with interrupts masked
+//! take a `&CriticalSection` to prove that the caller is in one.
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+
+/// Proof that interrupts are masked on the current core.
+///
+/// The token can not be sent anywhere else or outlive the `free` call that
+/// created it.
+pub struct CriticalSection {
+    _0: PhantomData<*const ()>,
+}
+
+impl CriticalSection {
+    /// Creates a critical section token.
+    ///
+    /// # Safety
+    ///
+    /// IRQs and FIQs have to be masked for the lifetime of the token, which is
+    /// the case in exception handlers.
+    pub unsafe fn new() -> Self {
+        CriticalSection { _0: PhantomData }
+    }
+}
+
+/// Masks IRQs and FIQs.
+pub fn disable() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("msr daifset, #3" ::: "memory" : "volatile");
+    }
+}
+
+/// Unmasks IRQs and FIQs.
+///
+/// # Safety
+///
+/// This must not be called inside a critical section since it would end it
+/// early.
+pub unsafe fn enable() {
+    #[cfg(target_arch = "aarch64")]
+    asm!("msr daifclr, #3" ::: "memory" : "volatile");
+}
+
+/// True if IRQs are masked, e.g. in a critical section or exception handler.
+#[cfg(target_arch = "aarch64")]
+pub fn irqs_masked() -> bool {
+    use cortex_a::regs::*;
+
+    DAIF.is_set(DAIF::I)
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn irqs_masked() -> bool {
+    false
+}
+
+/// Unmasks SError interrupts. They are left unmasked in critical sections.
+pub fn enable_serror() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("msr daifclr, #4" ::: "memory" : "volatile");
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn save_daif() -> u32 {
+    use cortex_a::regs::*;
+
+    let daif = DAIF.get();
+    disable();
+    daif
+}
+
+#[cfg(target_arch = "aarch64")]
+fn restore_daif(daif: u32) {
+    use cortex_a::regs::*;
+
+    DAIF.set(daif);
+}
+
+/// Runs `f` with IRQs and FIQs masked.
+///
+/// The previous DAIF state is restored afterwards instead of unconditionally
+/// unmasking, so critical sections can be nested and used from exception
+/// handlers.
+pub fn free<F, R>(f: F) -> R
+where
+    F: FnOnce(&CriticalSection) -> R,
+{
+    #[cfg(target_arch = "aarch64")]
+    let daif = save_daif();
+
+    let r = f(&unsafe { CriticalSection::new() });
+
+    #[cfg(target_arch = "aarch64")]
+    restore_daif(daif);
+
+    r
+}
+
+/// Data that is shared with interrupt handlers and only accessible inside a
+/// critical section.
+///
+/// There is only one core running salmiak so masking interrupts is enough to
+/// get exclusive access. Use a `Cell` or `RefCell` inside for mutability.
+pub struct Mutex<T> {
+    inner: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    /// Borrows the data for the duration of the critical section.
+    pub fn borrow<'cs>(&'cs self, _cs: &'cs CriticalSection) -> &'cs T {
+        unsafe { &*self.inner.get() }
+    }
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    static COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+    #[test]
+    fn nested_critical_sections() {
+        let r = free(|cs| {
+            COUNTER.borrow(cs).set(1);
+            free(|cs| {
+                let counter = COUNTER.borrow(cs);
+                counter.set(counter.get() + 1);
+            });
+            COUNTER.borrow(cs).get()
+        });
+
+        assert_eq!(r, 2);
+    }
+}
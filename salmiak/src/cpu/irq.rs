@@ -0,0 +1,140 @@
+//! Dispatch of peripheral (GPU) interrupts.
+//!
+//! The BCM2837 has two levels of interrupt controllers. The ARM local
+//! controller at 0x4000_0000 reports the per core timers and whether any of
+//! the 64 GPU interrupts are pending, which are then found in the GPU
+//! controller (BCM2835 ARM Peripherals, section 7).
+use super::interrupt::{self, Mutex};
+use crate::logger::warn;
+use crate::prelude::*;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub type Handler = fn();
+
+const IRQ_BASE: u32 = mem_constants::MMIO_BASE + 0xB000;
+const IRQ_PENDING_1: *mut u32 = (IRQ_BASE + 0x204) as *mut u32;
+const IRQ_PENDING_2: *mut u32 = (IRQ_BASE + 0x208) as *mut u32;
+const ENABLE_IRQS_1: *mut u32 = (IRQ_BASE + 0x210) as *mut u32;
+const ENABLE_IRQS_2: *mut u32 = (IRQ_BASE + 0x214) as *mut u32;
+const DISABLE_IRQS_1: *mut u32 = (IRQ_BASE + 0x21C) as *mut u32;
+const DISABLE_IRQS_2: *mut u32 = (IRQ_BASE + 0x220) as *mut u32;
+
+/// Interrupt sources of core 0 in the ARM local controller.
+const CORE0_IRQ_SOURCE: *mut u32 = 0x4000_0060 as *mut u32;
+
+/// CNTPSIRQ, CNTPNSIRQ, CNTHPIRQ and CNTVIRQ.
+pub const LOCAL_TIMERS: u32 = 0xf;
+/// One or more GPU interrupts are pending.
+pub const LOCAL_GPU: u32 = 1 << 8;
+
+pub const GPU_IRQS: usize = 64;
+
+static HANDLERS: Mutex<RefCell<[Option<Handler>; GPU_IRQS]>> =
+    Mutex::new(RefCell::new([None; GPU_IRQS]));
+static COUNTS: Mutex<RefCell<[u32; GPU_IRQS]>> = Mutex::new(RefCell::new([0; GPU_IRQS]));
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn registers(irq: usize) -> (*mut u32, *mut u32, u32) {
+    let bit = 1 << (irq % 32);
+    if irq < 32 {
+        (ENABLE_IRQS_1, DISABLE_IRQS_1, bit)
+    } else {
+        (ENABLE_IRQS_2, DISABLE_IRQS_2, bit)
+    }
+}
+
+/// Calls `handler` when GPU interrupt `irq` fires and enables it. The handler
+/// has to clear the interrupt in its peripheral.
+pub fn register(irq: usize, handler: Handler) {
+    assert!(irq < GPU_IRQS, "GPU interrupt {} out of range", irq);
+
+    interrupt::free(|cs| {
+        HANDLERS.borrow(cs).borrow_mut()[irq] = Some(handler);
+
+        let (enable, _, bit) = registers(irq);
+        unsafe {
+            enable.write_volatile(bit);
+        }
+    });
+}
+
+/// Disables GPU interrupt `irq` and removes its handler.
+pub fn unregister(irq: usize) {
+    assert!(irq < GPU_IRQS, "GPU interrupt {} out of range", irq);
+
+    interrupt::free(|cs| {
+        let (_, disable, bit) = registers(irq);
+        unsafe {
+            disable.write_volatile(bit);
+        }
+
+        HANDLERS.borrow(cs).borrow_mut()[irq] = None;
+    });
+}
+
+/// Disables every GPU interrupt, handlers stay registered. Used before
+/// handing the machine to another kernel.
+pub fn disable_all() {
+    unsafe {
+        DISABLE_IRQS_1.write_volatile(0xffff_ffff);
+        DISABLE_IRQS_2.write_volatile(0xffff_ffff);
+    }
+}
+
+/// Pending interrupt sources of core 0, see `LOCAL_TIMERS` and `LOCAL_GPU`.
+pub fn local_pending() -> u32 {
+    unsafe { CORE0_IRQ_SOURCE.read_volatile() }
+}
+
+/// Number of times GPU interrupt `irq` has fired since boot.
+pub fn count(irq: usize) -> u32 {
+    interrupt::free(|cs| COUNTS.borrow(cs).borrow()[irq])
+}
+
+/// Returns true if GPU interrupt `irq` has a handler.
+pub fn is_registered(irq: usize) -> bool {
+    interrupt::free(|cs| HANDLERS.borrow(cs).borrow()[irq].is_some())
+}
+
+/// Number of local timer interrupts since boot.
+pub fn timer_count() -> u32 {
+    TIMER_COUNT.load(Ordering::Relaxed)
+}
+
+pub(crate) fn count_timer() {
+    TIMER_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+fn gpu_pending() -> u64 {
+    unsafe {
+        u64::from(IRQ_PENDING_1.read_volatile()) | u64::from(IRQ_PENDING_2.read_volatile()) << 32
+    }
+}
+
+/// Calls the handlers of all pending GPU interrupts.
+pub(crate) fn dispatch_gpu() {
+    let mut pending = gpu_pending();
+
+    while pending != 0 {
+        let irq = pending.trailing_zeros() as usize;
+        pending &= !(1 << irq);
+
+        let handler = interrupt::free(|cs| {
+            let mut counts = COUNTS.borrow(cs).borrow_mut();
+            counts[irq] = counts[irq].wrapping_add(1);
+            HANDLERS.borrow(cs).borrow()[irq]
+        });
+        match handler {
+            Some(handler) => handler(),
+            None => {
+                // it would keep firing since nobody clears it
+                warn!("unhandled GPU IRQ {}, disabling it", irq);
+                let (_, disable, bit) = registers(irq);
+                unsafe {
+                    disable.write_volatile(bit);
+                }
+            }
+        }
+    }
+}
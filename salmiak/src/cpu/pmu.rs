@@ -0,0 +1,356 @@
+//! Profiling with the performance monitor unit.
+//!
+//! `init` enables the cycle counter and up to `MAX_EVENTS` event counters.
+//! Code wrapped in `profile!` is then measured and the results are
+//! aggregated per zone name until `report` prints the average per frame.
+//!
+//! ```ignore
+//! salmiak::cpu::pmu::init(&[Event::L1DataCacheRefill, Event::InstructionsRetired]);
+//! profile!("swap", { gpu.swap() });
+//! ```
+use super::interrupt::{self, Mutex};
+use core::cell::RefCell;
+use core::fmt::{self, Display, Formatter, Write};
+
+/// Number of event counters used, the Cortex-A53 has six.
+pub const MAX_EVENTS: usize = 4;
+
+/// Number of distinct zone names that can be recorded between reports.
+pub const MAX_ZONES: usize = 16;
+
+const PMCR_E: u64 = 1 << 0;
+const PMCR_P: u64 = 1 << 1;
+const PMCR_C: u64 = 1 << 2;
+const PMCNTEN_CYCLES: u64 = 1 << 31;
+
+static PROFILER: Mutex<RefCell<Profiler>> = Mutex::new(RefCell::new(Profiler::new()));
+
+/// Common architectural events (AArch64 Reference Manual, section D7.10).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    L1InstructionCacheRefill = 0x01,
+    L1DataCacheRefill = 0x03,
+    L1DataCacheAccess = 0x04,
+    InstructionsRetired = 0x08,
+    ExceptionsTaken = 0x09,
+    BranchMispredicted = 0x10,
+    BranchPredicted = 0x12,
+    MemoryAccess = 0x13,
+    L2DataCacheAccess = 0x16,
+    L2DataCacheRefill = 0x17,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Event::L1InstructionCacheRefill => "l1i refill",
+            Event::L1DataCacheRefill => "l1d refill",
+            Event::L1DataCacheAccess => "l1d access",
+            Event::InstructionsRetired => "instructions",
+            Event::ExceptionsTaken => "exceptions",
+            Event::BranchMispredicted => "mispredicts",
+            Event::BranchPredicted => "branches",
+            Event::MemoryAccess => "mem access",
+            Event::L2DataCacheAccess => "l2d access",
+            Event::L2DataCacheRefill => "l2d refill",
+        };
+        f.pad(name)
+    }
+}
+
+/// Counter values, or the difference between two readings.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sample {
+    pub cycles: u64,
+    pub events: [u64; MAX_EVENTS],
+}
+
+impl Sample {
+    /// Counts between `start` and this sample. The event counters are 32
+    /// bits wide and allowed to wrap once.
+    pub fn since(&self, start: &Sample) -> Sample {
+        let mut events = [0; MAX_EVENTS];
+        for (i, e) in events.iter_mut().enumerate() {
+            *e = u64::from((self.events[i] as u32).wrapping_sub(start.events[i] as u32));
+        }
+
+        Sample {
+            cycles: self.cycles.wrapping_sub(start.cycles),
+            events,
+        }
+    }
+
+    fn add(&mut self, other: &Sample) {
+        self.cycles += other.cycles;
+        for (e, o) in self.events.iter_mut().zip(other.events.iter()) {
+            *e += o;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Zone {
+    name: &'static str,
+    calls: u32,
+    total: Sample,
+}
+
+/// Aggregates samples per zone name.
+pub struct Profiler {
+    events: [Option<Event>; MAX_EVENTS],
+    zones: [Option<Zone>; MAX_ZONES],
+    frames: u32,
+    dropped: u32,
+}
+
+impl Profiler {
+    pub const fn new() -> Self {
+        Profiler {
+            events: [None; MAX_EVENTS],
+            zones: [None; MAX_ZONES],
+            frames: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn set_events(&mut self, events: &[Event]) {
+        self.events = [None; MAX_EVENTS];
+        for (slot, event) in self.events.iter_mut().zip(events) {
+            *slot = Some(*event);
+        }
+    }
+
+    pub fn record(&mut self, name: &'static str, sample: &Sample) {
+        let index = self
+            .zones
+            .iter()
+            .position(|z| z.map_or(true, |z| z.name == name));
+
+        match index {
+            Some(i) => {
+                let zone = self.zones[i].get_or_insert(Zone {
+                    name,
+                    calls: 0,
+                    total: Sample::default(),
+                });
+                zone.calls += 1;
+                zone.total.add(sample);
+            }
+            None => self.dropped += 1,
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones[0].is_none()
+    }
+
+    /// Writes the average counts per frame of every zone.
+    pub fn write_report<W: Write>(&self, w: &mut W) -> fmt::Result {
+        let frames = u64::from(self.frames.max(1));
+
+        write!(w, "{:<16} {:>6} {:>12}", "zone", "calls", "cycles")?;
+        for event in self.events.iter().filter_map(|e| *e) {
+            write!(w, " {:>12}", event)?;
+        }
+        writeln!(w)?;
+
+        for zone in self.zones.iter().filter_map(|z| *z) {
+            write!(
+                w,
+                "{:<16} {:>6} {:>12}",
+                zone.name,
+                u64::from(zone.calls) / frames,
+                zone.total.cycles / frames
+            )?;
+            for (i, _) in self.events.iter().enumerate().filter(|(_, e)| e.is_some()) {
+                write!(w, " {:>12}", zone.total.events[i] / frames)?;
+            }
+            writeln!(w)?;
+        }
+
+        if self.dropped > 0 {
+            writeln!(w, "{} samples dropped, too many zones", self.dropped)?;
+        }
+        write!(w, "averaged over {} frames", frames)
+    }
+
+    pub fn reset(&mut self) {
+        self.zones = [None; MAX_ZONES];
+        self.frames = 0;
+        self.dropped = 0;
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn select_counter(n: usize) {
+    unsafe {
+        asm!("msr pmselr_el0, $0
+              isb"
+             :
+             : "r"(n as u64)
+             : "memory"
+             : "volatile");
+    }
+}
+
+/// Enables the cycle counter and counts `events` in the event counters,
+/// only the first `MAX_EVENTS` are used. Resets all counters.
+pub fn init(events: &[Event]) {
+    let events = &events[..events.len().min(MAX_EVENTS)];
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        for (n, event) in events.iter().enumerate() {
+            select_counter(n);
+            // the filter bits are left as zero to count at all ELs
+            asm!("msr pmxevtyper_el0, $0" :: "r"(*event as u64) :: "volatile");
+        }
+
+        let enabled = PMCNTEN_CYCLES | ((1 << events.len()) - 1);
+        asm!("msr pmccfiltr_el0, xzr
+              msr pmcntenset_el0, $0
+              msr pmcr_el0, $1
+              isb"
+             :
+             : "r"(enabled), "r"(PMCR_E | PMCR_P | PMCR_C)
+             : "memory"
+             : "volatile");
+    }
+
+    interrupt::free(|cs| {
+        let mut profiler = PROFILER.borrow(cs).borrow_mut();
+        profiler.reset();
+        profiler.set_events(events);
+    });
+}
+
+/// Reads the cycle counter and the event counters.
+#[cfg(target_arch = "aarch64")]
+pub fn read() -> Sample {
+    let mut sample = Sample::default();
+
+    unsafe {
+        asm!("mrs $0, pmccntr_el0" : "=r"(sample.cycles) ::: "volatile");
+        for (n, event) in sample.events.iter_mut().enumerate() {
+            select_counter(n);
+            asm!("mrs $0, pmxevcntr_el0" : "=r"(*event) ::: "volatile");
+        }
+    }
+
+    sample
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn read() -> Sample {
+    Sample::default()
+}
+
+/// Adds a measurement of zone `name`, usually called through `profile!`.
+pub fn record(name: &'static str, sample: &Sample) {
+    interrupt::free(|cs| PROFILER.borrow(cs).borrow_mut().record(name, sample));
+}
+
+/// Marks the end of a frame, the report is averaged over frames.
+pub fn end_frame() {
+    interrupt::free(|cs| PROFILER.borrow(cs).borrow_mut().end_frame());
+}
+
+/// Prints the averages since the last report over serial and starts over.
+pub fn report() {
+    interrupt::free(|cs| {
+        let mut profiler = PROFILER.borrow(cs).borrow_mut();
+        if profiler.is_empty() {
+            return;
+        }
+
+        let mut writer = crate::serial::SerialWriter;
+        let _ = profiler.write_report(&mut writer);
+        crate::serial::write("\n");
+        profiler.reset();
+    });
+}
+
+/// Measures the cycles and events of a block and records them under a name.
+/// Evaluates to the value of the block.
+#[macro_export]
+macro_rules! profile {
+    ($name:expr, $body:block) => {{
+        let start = $crate::cpu::pmu::read();
+        let result = $body;
+        $crate::cpu::pmu::record($name, &$crate::cpu::pmu::read().since(&start));
+        result
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_since_wraps() {
+        let start = Sample {
+            cycles: 100,
+            events: [0xffff_fff0, 5, 0, 0],
+        };
+        let end = Sample {
+            cycles: 350,
+            events: [0x10, 7, 0, 0],
+        };
+
+        assert_eq!(
+            end.since(&start),
+            Sample {
+                cycles: 250,
+                events: [0x20, 2, 0, 0],
+            }
+        );
+    }
+
+    #[test]
+    fn report_per_frame() {
+        let mut profiler = Profiler::new();
+        profiler.set_events(&[Event::L1DataCacheRefill]);
+
+        let sample = Sample {
+            cycles: 1000,
+            events: [10, 0, 0, 0],
+        };
+        for _ in 0..2 {
+            profiler.record("render", &sample);
+            profiler.record("render", &sample);
+            profiler.record("swap", &sample);
+            profiler.end_frame();
+        }
+
+        let mut report = String::new();
+        profiler.write_report(&mut report).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "zone              calls       cycles   l1d refill"
+        );
+        assert_eq!(
+            lines[1],
+            "render                2         2000           20"
+        );
+        assert_eq!(
+            lines[2],
+            "swap                  1         1000           10"
+        );
+        assert_eq!(lines[3], "averaged over 2 frames");
+
+        profiler.reset();
+        assert!(profiler.is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+//! Kernel symbol table used to put names on addresses in backtraces.
+//!
+//! The table lives in the `.ksyms` section and is written into the linked
+//! kernel ELF by the `ksyms` tool before the image is created. Layout, all
+//! fields little endian:
+//!
+//! ```text
+//! header:  magic "KSYM" | u32 entry count | u32 string table offset | u32 reserved
+//! entries: u64 address | u32 size | u32 name offset | u32 name length | u32 reserved
+//! strings: names, not null terminated
+//! ```
+//!
+//! Entries are sorted by address.
+use crate::prelude::*;
+use core::str;
+
+pub const MAGIC: &[u8; 4] = b"KSYM";
+pub const HEADER_SIZE: usize = 16;
+pub const ENTRY_SIZE: usize = 24;
+
+/// Space reserved for the table in the kernel image.
+pub const RESERVED_SIZE: usize = 128 * 1024;
+
+#[cfg(target_arch = "aarch64")]
+#[link_section = ".ksyms"]
+#[used]
+static KSYMS: [u8; RESERVED_SIZE] = [0; RESERVED_SIZE];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Symbol<'a> {
+    pub address: u64,
+    pub size: u32,
+    pub name: &'a str,
+}
+
+pub struct SymbolTable<'a> {
+    data: &'a [u8],
+    count: usize,
+    strings: usize,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Returns `None` if `data` does not contain a valid table.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(data, 4) as usize;
+        let strings = read_u32(data, 8) as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > strings || strings > data.len() {
+            return None;
+        }
+
+        Some(SymbolTable {
+            data,
+            count,
+            strings,
+        })
+    }
+
+    /// The table embedded in the running kernel, if the ksyms tool has been run.
+    #[cfg(target_arch = "aarch64")]
+    pub fn kernel() -> Option<SymbolTable<'static>> {
+        extern "C" {
+            static __ksyms_start: u8;
+            static __ksyms_end: u8;
+        }
+
+        // Go through the linker symbols rather than `KSYMS` so that the
+        // compiler cannot assume the table is all zeroes.
+        unsafe {
+            let start = &__ksyms_start as *const u8;
+            let len = &__ksyms_end as *const u8 as usize - start as usize;
+            SymbolTable::parse(core::slice::from_raw_parts(start, len))
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        if index >= self.count {
+            return None;
+        }
+
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        let name_start = self.strings + read_u32(self.data, entry + 12) as usize;
+        let name_end = name_start + read_u32(self.data, entry + 16) as usize;
+        let name = self
+            .data
+            .get(name_start..name_end)
+            .and_then(|n| str::from_utf8(n).ok())
+            .unwrap_or("?");
+
+        Some(Symbol {
+            address: read_u64(self.data, entry),
+            size: read_u32(self.data, entry + 8),
+            name,
+        })
+    }
+
+    /// Finds the symbol containing `address` and the offset into it.
+    pub fn lookup(&self, address: u64) -> Option<(Symbol<'a>, u64)> {
+        // find the last symbol starting at or before the address
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.get(mid)?.address <= address {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let symbol = self.get(low.checked_sub(1)?)?;
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= u64::from(symbol.size) {
+            return None;
+        }
+
+        Some((symbol, offset))
+    }
+}
+
+/// Builds a table from `symbols`, which has to be sorted by address.
+pub fn encode(symbols: &[Symbol]) -> Vec<u8> {
+    let strings = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+    let mut data = Vec::with_capacity(strings);
+    let mut names: Vec<u8> = Vec::new();
+
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    data.extend_from_slice(&(strings as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+
+    for symbol in symbols {
+        data.extend_from_slice(&symbol.address.to_le_bytes());
+        data.extend_from_slice(&symbol.size.to_le_bytes());
+        data.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(symbol.name.len() as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+    }
+
+    data.extend_from_slice(&names);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Vec<Symbol<'static>> {
+        vec![
+            Symbol {
+                address: 0x8_0000,
+                size: 0x10,
+                name: "_start",
+            },
+            Symbol {
+                address: 0x8_0100,
+                size: 0x80,
+                name: "salmiak::main::reset",
+            },
+            Symbol {
+                address: 0x8_0200,
+                size: 0,
+                name: "sneka::entry::boot",
+            },
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = encode(&symbols());
+        let table = SymbolTable::parse(&data).unwrap();
+
+        assert_eq!(table.len(), 3);
+        for (i, symbol) in symbols().iter().enumerate() {
+            assert_eq!(table.get(i).as_ref(), Some(symbol));
+        }
+        assert_eq!(table.get(3), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let data = encode(&symbols());
+        let table = SymbolTable::parse(&data).unwrap();
+
+        let (symbol, offset) = table.lookup(0x8_0124).unwrap();
+        assert_eq!(symbol.name, "salmiak::main::reset");
+        assert_eq!(offset, 0x24);
+
+        assert_eq!(table.lookup(0x8_0000).unwrap().0.name, "_start");
+        assert_eq!(table.lookup(0x9_0000).unwrap().0.name, "sneka::entry::boot");
+
+        // before the first symbol and in the gap after _start
+        assert!(table.lookup(0x7_ffff).is_none());
+        assert!(table.lookup(0x8_0010).is_none());
+    }
+
+    #[test]
+    fn invalid_table() {
+        assert!(SymbolTable::parse(&[0; RESERVED_SIZE]).is_none());
+        assert!(SymbolTable::parse(b"KSYM").is_none());
+
+        let mut data = encode(&symbols());
+        data.truncate(HEADER_SIZE + ENTRY_SIZE);
+        assert!(SymbolTable::parse(&data).is_none());
+    }
+}
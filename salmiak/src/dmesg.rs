@@ -0,0 +1,150 @@
+//! Kernel log that survives warm resets.
+//!
+//! Everything printed with `sprintln!` and logged to the serial console is
+//! also kept here. The log lives in the `.dmesg` section, which the linker
+//! script puts at a fixed address outside of `.bss` so that it is neither
+//! loaded nor zeroed. `init` checks the magic header and keeps what the last
+//! boot wrote as the previous log, so it can be printed after a crash and a
+//! `power::reset`.
+use crate::cpu::interrupt::{self, Mutex};
+use crate::ring_buffer::TextRing;
+use crate::serial;
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+
+/// Marks memory holding a log, "DMESGLOG".
+const MAGIC: u64 = 0x474f_4c47_5345_4d44;
+
+#[repr(C)]
+struct PersistentLog {
+    magic: u64,
+    previous: TextRing,
+    current: TextRing,
+}
+
+impl PersistentLog {
+    /// Keeps the current log as the previous one if the header shows that
+    /// there is one, then starts an empty log.
+    fn start(&mut self) {
+        if self.magic == MAGIC {
+            self.previous.copy_from(&self.current);
+        } else {
+            self.previous.clear();
+        }
+
+        self.current.clear();
+        self.magic = MAGIC;
+    }
+}
+
+#[cfg_attr(target_arch = "aarch64", link_section = ".dmesg")]
+static mut LOG: MaybeUninit<PersistentLog> = MaybeUninit::uninit();
+
+/// Set by `init`, before that the memory may be anything.
+static READY: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Which boot to read the log of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boot {
+    Current,
+    Previous,
+}
+
+/// Picks up the log of the last boot and starts a new one. Has to run before
+/// anything is printed, output before that is not kept.
+pub fn init() {
+    interrupt::free(|cs| unsafe {
+        (*LOG.as_mut_ptr()).start();
+        READY.borrow(cs).set(true);
+    });
+}
+
+fn with_log<R, F: FnOnce(&mut PersistentLog) -> R>(f: F) -> Option<R> {
+    interrupt::free(|cs| {
+        if READY.borrow(cs).get() {
+            Some(f(unsafe { &mut *LOG.as_mut_ptr() }))
+        } else {
+            None
+        }
+    })
+}
+
+/// Adds `s` to the log of this boot.
+pub fn write(s: &str) {
+    with_log(|log| log.current.push(s.as_bytes()));
+}
+
+/// Writes the log of `boot` to `w`, oldest line first.
+pub fn write_to<W: Write>(boot: Boot, w: &mut W) -> fmt::Result {
+    with_log(|log| match boot {
+        Boot::Current => log.current.write_to(w),
+        Boot::Previous => log.previous.write_to(w),
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// True if the last boot left a log behind.
+pub fn has_previous() -> bool {
+    with_log(|log| !log.previous.is_empty()).unwrap_or(false)
+}
+
+/// Writes the log out of the data cache so that it is in memory when the
+/// board resets.
+#[cfg(target_arch = "aarch64")]
+pub fn flush() {
+    const CACHE_LINE: usize = 64;
+    let start = unsafe { LOG.as_ptr() } as usize;
+    let end = start + core::mem::size_of::<PersistentLog>();
+
+    for line in (start & !(CACHE_LINE - 1)..end).step_by(CACHE_LINE) {
+        unsafe {
+            asm!("dc cvac, $0" : : "r"(line) : "memory" : "volatile");
+        }
+    }
+
+    unsafe {
+        asm!("dsb sy" : : : "memory" : "volatile");
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn flush() {}
+
+/// Writes to the serial console and the log, used by `sprintln!`.
+pub struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::write(s);
+        write(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_previous_boot() {
+        let mut log: PersistentLog = unsafe { core::mem::zeroed() };
+        log.current.push(b"garbage\n");
+
+        log.start();
+        assert!(log.previous.is_empty());
+        assert!(log.current.is_empty());
+
+        log.current.push(b"first boot\n");
+        log.start();
+        log.current.push(b"second boot\n");
+
+        let mut text = String::new();
+        log.previous.write_to(&mut text).unwrap();
+        assert_eq!(text, "first boot\n");
+
+        let mut text = String::new();
+        log.current.write_to(&mut text).unwrap();
+        assert_eq!(text, "second boot\n");
+    }
+}
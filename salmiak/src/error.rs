@@ -7,6 +7,8 @@ pub enum SalmiakErrorKind {
 
     InitGPUError(String),
     InitSerialError(String),
+    ChainloadError(String),
+    TransferError(String),
 }
 
 #[derive(Debug)]
@@ -32,6 +34,8 @@ impl Display for SalmiakError {
             SalmiakErrorKind::InitCPUError(mess) => write!(f, "{}", mess),
             SalmiakErrorKind::InitGPUError(mess) => write!(f, "{}", mess),
             SalmiakErrorKind::InitSerialError(mess) => write!(f, "{}", mess),
+            SalmiakErrorKind::ChainloadError(mess) => write!(f, "{}", mess),
+            SalmiakErrorKind::TransferError(mess) => write!(f, "{}", mess),
         }
     }
 }
@@ -1,11 +1,65 @@
 // exception handling
+
+// layout of the frame pushed by kernel_entry, see ExceptionFrame in cpu/exception.rs
+.equ FRAME_FP, 16 * 17
+.equ FRAME_FPCR, FRAME_FP + 16 * 32
+.equ FRAME_FPSR, FRAME_FPCR + 8
+.equ FRAME_SIZE, FRAME_FPSR + 8
+
 .macro ventry label
 .align 7
 	b \label
 .endm
 
+// stores q0-q31, fpcr and fpsr as an FpState at base + offset, uses x9 and x10
+.macro	fp_save base, offset
+	stp	q0, q1, [\base, #\offset + 32 * 0]
+	stp	q2, q3, [\base, #\offset + 32 * 1]
+	stp	q4, q5, [\base, #\offset + 32 * 2]
+	stp	q6, q7, [\base, #\offset + 32 * 3]
+	stp	q8, q9, [\base, #\offset + 32 * 4]
+	stp	q10, q11, [\base, #\offset + 32 * 5]
+	stp	q12, q13, [\base, #\offset + 32 * 6]
+	stp	q14, q15, [\base, #\offset + 32 * 7]
+	stp	q16, q17, [\base, #\offset + 32 * 8]
+	stp	q18, q19, [\base, #\offset + 32 * 9]
+	stp	q20, q21, [\base, #\offset + 32 * 10]
+	stp	q22, q23, [\base, #\offset + 32 * 11]
+	stp	q24, q25, [\base, #\offset + 32 * 12]
+	stp	q26, q27, [\base, #\offset + 32 * 13]
+	stp	q28, q29, [\base, #\offset + 32 * 14]
+	stp	q30, q31, [\base, #\offset + 32 * 15]
+	mrs	x9, fpcr
+	mrs	x10, fpsr
+	str	x9, [\base, #\offset + 16 * 32]
+	str	x10, [\base, #\offset + 16 * 32 + 8]
+.endm
+
+.macro	fp_restore base, offset
+	ldr	x9, [\base, #\offset + 16 * 32]
+	ldr	x10, [\base, #\offset + 16 * 32 + 8]
+	msr	fpcr, x9
+	msr	fpsr, x10
+	ldp	q0, q1, [\base, #\offset + 32 * 0]
+	ldp	q2, q3, [\base, #\offset + 32 * 1]
+	ldp	q4, q5, [\base, #\offset + 32 * 2]
+	ldp	q6, q7, [\base, #\offset + 32 * 3]
+	ldp	q8, q9, [\base, #\offset + 32 * 4]
+	ldp	q10, q11, [\base, #\offset + 32 * 5]
+	ldp	q12, q13, [\base, #\offset + 32 * 6]
+	ldp	q14, q15, [\base, #\offset + 32 * 7]
+	ldp	q16, q17, [\base, #\offset + 32 * 8]
+	ldp	q18, q19, [\base, #\offset + 32 * 9]
+	ldp	q20, q21, [\base, #\offset + 32 * 10]
+	ldp	q22, q23, [\base, #\offset + 32 * 11]
+	ldp	q24, q25, [\base, #\offset + 32 * 12]
+	ldp	q26, q27, [\base, #\offset + 32 * 13]
+	ldp	q28, q29, [\base, #\offset + 32 * 14]
+	ldp	q30, q31, [\base, #\offset + 32 * 15]
+.endm
+
 .macro	kernel_entry
-	sub	sp, sp, 512
+	sub	sp, sp, #FRAME_SIZE
 	stp	x0, x1, [sp, #16 * 0]
 	stp	x2, x3, [sp, #16 * 1]
 	stp	x4, x5, [sp, #16 * 2]
@@ -23,12 +77,19 @@
 	stp	x28, x29, [sp, #16 * 14]
 	mrs	x22, elr_el1
 	mrs	x23, spsr_el1
+	add	x21, sp, #FRAME_SIZE
 
 	stp	x30, x22, [sp, #16 * 15]
-	str	x23, [sp, #16 * 16]
+	stp	x23, x21, [sp, #16 * 16]
+
+	// the interrupted code may be in the middle of using the FPU, and
+	// handlers are free to use it as well
+	fp_save	sp, FRAME_FP
 .endm
 
 .macro	kernel_exit
+	fp_restore	sp, FRAME_FP
+
 	ldr	x23, [sp, #16 * 16]
 	ldp	x30, x22, [sp, #16 * 15]
 
@@ -50,24 +111,10 @@
 	ldp	x24, x25, [sp, #16 * 12]
 	ldp	x26, x27, [sp, #16 * 13]
 	ldp	x28, x29, [sp, #16 * 14]
-	add	sp, sp, 512
+	add	sp, sp, #FRAME_SIZE
 	eret
 .endm
 
-.macro unhandled_exception type
-	kernel_entry
-	mov x0, #\type
-	mrs x1, esr_el1
-	mrs x2, elr_el1
-	mrs x3, far_el1
-	bl print_unhandled_exception
-	b honeypot // send to honeypot
-.endm
-
-honeypot:
-	nop
-	b honeypot
-
 .align	11
 .section .vectors, "ax"
 .globl _vectors
@@ -90,25 +137,34 @@ _vectors:
 	ventry	error		// Error EL1 (with EL1 stack)
 
 sync:
-	unhandled_exception 0
+	kernel_entry
+	mov x0, sp
+	bl handle_sync
+	kernel_exit
 
 irq:
 	kernel_entry
+	mov x0, sp
 	bl handle_irq
 	kernel_exit
 
 fiq:
-	unhandled_exception 2
+	kernel_entry
+	mov x0, sp
+	bl handle_fiq
+	kernel_exit
 
 error:
-	unhandled_exception 3
+	kernel_entry
+	mov x0, sp
+	bl handle_serror
+	kernel_exit
 
-.globl enable_irq
-enable_irq:
-	msr daifclr, #2
-	ret
+.section .text
 
-.globl disable_irq
-disable_irq:
-	msr daifset, #2
+// saves the FpState at x0 and restores it again, to time that on its own
+.globl fp_save_restore
+fp_save_restore:
+	fp_save	x0, 0
+	fp_restore	x0, 0
 	ret
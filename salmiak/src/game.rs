@@ -0,0 +1,232 @@
+//! Fixed timestep game loop.
+//!
+//! The simulation is advanced in steps of a fixed length so that it behaves
+//! the same no matter how long rendering takes. Rendering happens once per
+//! frame and gets how far into the next step the loop is, for interpolating
+//! between the previous and current state.
+//!
+//! Input is read once per frame, before the updates, and passed to
+//! `Game::input`. After rendering the screen is also drawn to the serial
+//! console if `gpu::terminal` is enabled.
+use crate::cpu::pmu;
+use crate::gpu::{terminal, Gpu};
+use crate::input::{self, InputState};
+use crate::timer::{self, Duration, Instant};
+
+/// Frames taking longer than this only advance the simulation by this much,
+/// otherwise a slow frame leads to even more steps in the next one.
+const MAX_FRAME_TIME: Duration = Duration::from_millis(250);
+
+/// How often `Game::report` is called.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub trait Game {
+    /// Called once per frame with what was pressed since the last one,
+    /// before the updates of the frame.
+    fn input(&mut self, _input: &InputState) {}
+
+    /// Advances the simulation by `dt`, which is always the step given to
+    /// the `GameLoop`.
+    fn update(&mut self, dt: Duration);
+
+    /// Draws the current state. `alpha` in [0, 1) is how far the loop has
+    /// come towards the next update.
+    fn render(&mut self, gpu: &mut Gpu, alpha: f32);
+
+    /// Called with the frame statistics about once a second, right before
+    /// the `cpu::pmu` report. Not while the terminal view is on.
+    fn report(&mut self, stats: &FrameStats) {
+        sprintln!("{}", stats);
+    }
+}
+
+/// Keeps track of how much time the simulation is behind.
+#[derive(Debug)]
+pub struct Accumulator {
+    step: Duration,
+    accumulated: Duration,
+}
+
+impl Accumulator {
+    pub fn new(step: Duration) -> Self {
+        assert!(step > Duration::from_secs(0), "the step can not be zero");
+        Accumulator {
+            step,
+            accumulated: Duration::from_secs(0),
+        }
+    }
+
+    /// Adds the time of a frame and returns the number of steps to simulate.
+    pub fn advance(&mut self, frame_time: Duration) -> u32 {
+        self.accumulated += frame_time.min(MAX_FRAME_TIME);
+
+        let mut steps = 0;
+        while self.accumulated >= self.step {
+            self.accumulated -= self.step;
+            steps += 1;
+        }
+        steps
+    }
+
+    /// Fraction of a step left over after the last `advance`.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulated.as_nanos() as f64 / self.step.as_nanos() as f64) as f32
+    }
+}
+
+/// Frame time statistics over one reporting interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub frames: u32,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        FrameStats {
+            frames: 0,
+            total: Duration::from_secs(0),
+            min: Duration::from_secs(u64::max_value()),
+            max: Duration::from_secs(0),
+        }
+    }
+
+    pub fn add(&mut self, frame_time: Duration) {
+        self.frames += 1;
+        self.total += frame_time;
+        self.min = self.min.min(frame_time);
+        self.max = self.max.max(frame_time);
+    }
+
+    pub fn fps(&self) -> f32 {
+        if self.total == Duration::from_secs(0) {
+            return 0.0;
+        }
+
+        (f64::from(self.frames) * 1e9 / self.total.as_nanos() as f64) as f32
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.frames == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total / self.frames
+        }
+    }
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        FrameStats::new()
+    }
+}
+
+impl core::fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:.1} fps, frame time avg {} us, min {} us, max {} us",
+            self.fps(),
+            self.average().as_micros(),
+            self.min.as_micros(),
+            self.max.as_micros()
+        )
+    }
+}
+
+pub struct GameLoop {
+    step: Duration,
+    frame_cap: Option<Duration>,
+}
+
+impl GameLoop {
+    /// Creates a loop that updates the game every `step`.
+    pub fn new(step: Duration) -> Self {
+        GameLoop {
+            step,
+            frame_cap: None,
+        }
+    }
+
+    /// Limits rendering to `fps` frames per second, sleeping for the rest of
+    /// each frame.
+    pub fn frame_cap(mut self, fps: u32) -> Self {
+        self.frame_cap = Some(Duration::from_secs(1) / fps.max(1));
+        self
+    }
+
+    pub fn run<G: Game>(&self, game: &mut G, gpu: &mut Gpu) -> ! {
+        let mut accumulator = Accumulator::new(self.step);
+        let mut stats = FrameStats::new();
+        let mut last = Instant::now();
+        let mut last_report = last;
+
+        loop {
+            let frame_start = Instant::now();
+            let frame_time = frame_start - last;
+            last = frame_start;
+            stats.add(frame_time);
+
+            game.input(&input::update());
+            for _ in 0..accumulator.advance(frame_time) {
+                game.update(self.step);
+            }
+            game.render(gpu, accumulator.alpha());
+            terminal::present();
+            pmu::end_frame();
+
+            if frame_start - last_report >= REPORT_INTERVAL {
+                // reports would scroll the terminal view away
+                if !terminal::is_enabled() {
+                    game.report(&stats);
+                    // only prints if any `profile!` zones were recorded
+                    pmu::report();
+                }
+                stats = FrameStats::new();
+                last_report = frame_start;
+            }
+
+            if let Some(frame_cap) = self.frame_cap {
+                timer::sleep_until(frame_start + frame_cap);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulator_steps() {
+        let mut accumulator = Accumulator::new(Duration::from_millis(10));
+
+        assert_eq!(accumulator.advance(Duration::from_millis(4)), 0);
+        assert_eq!(accumulator.alpha(), 0.4);
+        assert_eq!(accumulator.advance(Duration::from_millis(17)), 2);
+        assert!((accumulator.alpha() - 0.1).abs() < 1e-6);
+
+        // a long stall does not make the simulation try to catch up forever
+        assert_eq!(accumulator.advance(Duration::from_secs(10)), 25);
+    }
+
+    #[test]
+    fn frame_stats() {
+        let mut stats = FrameStats::new();
+        assert_eq!(stats.fps(), 0.0);
+
+        stats.add(Duration::from_millis(10));
+        stats.add(Duration::from_millis(30));
+
+        assert_eq!(stats.fps(), 50.0);
+        assert_eq!(stats.average(), Duration::from_millis(20));
+        assert_eq!(stats.min, Duration::from_millis(10));
+        assert_eq!(stats.max, Duration::from_millis(30));
+        assert_eq!(
+            format!("{}", stats),
+            "50.0 fps, frame time avg 20000 us, min 10000 us, max 30000 us"
+        );
+    }
+}
@@ -0,0 +1,703 @@
+//! GDB remote serial protocol stub running over the serial console.
+//!
+//! Call `init` to enable debug exceptions and `breakpoint` to stop and wait
+//! for GDB. From there on software breakpoints (`Z0`) and single stepping
+//! are handled through the synchronous exception vector. Interrupting a
+//! running target with ctrl-c is not supported.
+//!
+//! Anything printed over serial while GDB is attached ends up in between
+//! the packets and is ignored by GDB.
+use crate::cpu::esr::{Esr, ExceptionClass};
+use crate::cpu::ExceptionFrame;
+use crate::serial;
+
+/// `brk #0`
+const BRK: u32 = 0xd420_0000;
+const MAX_BREAKPOINTS: usize = 16;
+const PACKET_SIZE: usize = 1024;
+
+/// Registers in the order GDB expects them for AArch64.
+const NUM_REGISTERS: usize = 34;
+const SP: usize = 31;
+const PC: usize = 32;
+const CPSR: usize = 33;
+
+// PSTATE bits in SPSR_EL1
+const SPSR_SS: u64 = 1 << 21;
+const SPSR_D: u64 = 1 << 9;
+
+// MDSCR_EL1 bits
+const MDSCR_SS: u64 = 1;
+const MDSCR_KDE: u64 = 1 << 13;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u32,
+}
+
+struct Stub {
+    enabled: bool,
+    attached: bool,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+static mut STUB: Stub = Stub::new();
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply,
+    Resume { step: bool },
+    Detach,
+}
+
+/// Outgoing packet data, without framing.
+struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Packet {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            self.push(b);
+        }
+    }
+
+    fn push_hex(&mut self, b: u8) {
+        self.push(hex_char(b >> 4));
+        self.push(hex_char(b & 0xf));
+    }
+
+    /// Pushes `size` bytes of `value` in target (little endian) byte order.
+    fn push_le(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            self.push_hex((value >> (i * 8)) as u8);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+fn hex_char(nibble: u8) -> u8 {
+    b"0123456789abcdef"[nibble as usize]
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+
+    s.iter()
+        .try_fold(0u64, |acc, &c| Some((acc << 4) | u64::from(hex_digit(c)?)))
+}
+
+/// Parses hex encoded bytes in target byte order.
+fn parse_le(s: &[u8]) -> Option<u64> {
+    if s.len() % 2 != 0 || s.len() > 16 {
+        return None;
+    }
+
+    s.chunks(2).enumerate().try_fold(0u64, |acc, (i, pair)| {
+        let b = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+        Some(acc | (u64::from(b) << (i * 8)))
+    })
+}
+
+/// Splits `addr,len` into its parts.
+fn parse_range(s: &[u8]) -> Option<(u64, usize)> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    Some((
+        parse_hex(&s[..comma])?,
+        parse_hex(&s[comma + 1..])? as usize,
+    ))
+}
+
+/// Parses the `addr,len:XX..` of an `M` packet, the bytes go into `buf`.
+fn parse_write<'a>(s: &[u8], buf: &'a mut [u8]) -> Option<(u64, &'a [u8])> {
+    let colon = s.iter().position(|&c| c == b':')?;
+    let (addr, len) = parse_range(&s[..colon])?;
+    let hex = &s[colon + 1..];
+    if len > buf.len() || hex.len() != len * 2 {
+        return None;
+    }
+
+    for (b, pair) in buf.iter_mut().zip(hex.chunks(2)) {
+        *b = parse_le(pair)? as u8;
+    }
+    Some((addr, &buf[..len]))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))
+}
+
+fn read_register(frame: &ExceptionFrame, n: usize) -> Option<(u64, usize)> {
+    match n {
+        0..=30 => Some((frame.regs[n], 8)),
+        SP => Some((frame.sp, 8)),
+        PC => Some((frame.elr, 8)),
+        CPSR => Some((frame.spsr & 0xffff_ffff, 4)),
+        _ => None,
+    }
+}
+
+fn write_register(frame: &mut ExceptionFrame, n: usize, value: u64) -> bool {
+    match n {
+        0..=30 => frame.regs[n] = value,
+        // the stack pointer is not restored from the frame
+        SP => (),
+        PC => frame.elr = value,
+        CPSR => frame.spsr = (frame.spsr & !0xffff_ffff) | (value & 0xffff_ffff),
+        _ => return false,
+    }
+    true
+}
+
+/// Returns true if the `len` bytes at `addr` can be accessed, GDB probes
+/// arbitrary addresses e.g. while unwinding.
+#[cfg(target_arch = "aarch64")]
+fn is_mapped(addr: u64, len: usize) -> bool {
+    crate::memory::is_mapped(addr as usize, len)
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn is_mapped(addr: u64, len: usize) -> bool {
+    addr.checked_add(len as u64).is_some()
+}
+
+unsafe fn read_memory(addr: u64) -> u8 {
+    (addr as *const u8).read_volatile()
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn write_memory(addr: u64, byte: u8) {
+    if crate::memory::is_read_only(addr as usize) {
+        let word_addr = addr & !0b11;
+        let shift = (addr & 0b11) * 8;
+        let word = (word_addr as *const u32).read_volatile();
+        write_instruction(
+            word_addr,
+            (word & !(0xff << shift)) | (u32::from(byte) << shift),
+        );
+    } else {
+        (addr as *mut u8).write_volatile(byte);
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+unsafe fn write_memory(addr: u64, byte: u8) {
+    (addr as *mut u8).write_volatile(byte);
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn write_instruction(addr: u64, insn: u32) {
+    crate::memory::write_instruction(addr as usize, insn);
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+unsafe fn write_instruction(addr: u64, insn: u32) {
+    (addr as *mut u32).write_volatile(insn);
+}
+
+#[cfg(target_arch = "aarch64")]
+fn modify_mdscr(set: u64, clear: u64) {
+    unsafe {
+        let mut mdscr: u64;
+        asm!("mrs $0, mdscr_el1" : "=r"(mdscr) ::: "volatile");
+        mdscr = (mdscr & !clear) | set;
+        asm!("msr mdscr_el1, $0
+              isb"
+             :
+             : "r"(mdscr)
+             :
+             : "volatile");
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn modify_mdscr(_set: u64, _clear: u64) {}
+
+impl Stub {
+    const fn new() -> Self {
+        Stub {
+            enabled: false,
+            attached: false,
+            breakpoints: [None; MAX_BREAKPOINTS],
+        }
+    }
+
+    fn is_breakpoint(&self, address: u64) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|b| b.map_or(false, |b| b.address == address))
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if address % 4 != 0 {
+            return false;
+        }
+
+        if self.is_breakpoint(address) {
+            return true;
+        }
+
+        match self.breakpoints.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => unsafe {
+                *slot = Some(Breakpoint {
+                    address,
+                    original: (address as *const u32).read_volatile(),
+                });
+                write_instruction(address, BRK);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(b) = *slot {
+                if b.address == address {
+                    unsafe {
+                        write_instruction(b.address, b.original);
+                    }
+                    *slot = None;
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(b) = slot.take() {
+                unsafe {
+                    write_instruction(b.address, b.original);
+                }
+            }
+        }
+    }
+
+    fn handle_packet(
+        &mut self,
+        packet: &[u8],
+        frame: &mut ExceptionFrame,
+        response: &mut Packet,
+    ) -> Action {
+        let (command, args) = match packet.split_first() {
+            Some((&command, args)) => (command, args),
+            None => return Action::Reply,
+        };
+
+        match command {
+            b'?' => response.push_str("S05"),
+            b'g' => {
+                for n in 0..NUM_REGISTERS {
+                    if let Some((value, size)) = read_register(frame, n) {
+                        response.push_le(value, size);
+                    }
+                }
+            }
+            b'G' => {
+                let mut offset = 0;
+                for n in 0..NUM_REGISTERS {
+                    let size = if n == CPSR { 4 } else { 8 };
+                    match args.get(offset..offset + size * 2).and_then(parse_le) {
+                        Some(value) => {
+                            write_register(frame, n, value);
+                        }
+                        None => break,
+                    }
+                    offset += size * 2;
+                }
+                response.push_str("OK");
+            }
+            b'p' => match parse_hex(args).and_then(|n| read_register(frame, n as usize)) {
+                Some((value, size)) => response.push_le(value, size),
+                None => response.push_str("E01"),
+            },
+            b'P' => {
+                let written = match args.iter().position(|&c| c == b'=') {
+                    Some(eq) => match (parse_hex(&args[..eq]), parse_le(&args[eq + 1..])) {
+                        (Some(n), Some(value)) => write_register(frame, n as usize, value),
+                        _ => false,
+                    },
+                    None => false,
+                };
+                response.push_str(if written { "OK" } else { "E01" });
+            }
+            b'm' => match parse_range(args) {
+                Some((addr, len)) if len <= PACKET_SIZE / 2 && is_mapped(addr, len) => {
+                    for i in 0..len as u64 {
+                        response.push_hex(unsafe { read_memory(addr + i) });
+                    }
+                }
+                _ => response.push_str("E01"),
+            },
+            b'M' => {
+                let mut buf = [0; PACKET_SIZE / 2];
+                let written = match parse_write(args, &mut buf) {
+                    Some((addr, data)) if is_mapped(addr, data.len()) => {
+                        for (i, &b) in data.iter().enumerate() {
+                            unsafe { write_memory(addr + i as u64, b) }
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+                response.push_str(if written { "OK" } else { "E01" });
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.elr = addr;
+                }
+                return Action::Resume {
+                    step: command == b's',
+                };
+            }
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let ok = match parse_range(&args[2..]) {
+                    Some((addr, _)) if command == b'Z' => self.insert_breakpoint(addr),
+                    Some((addr, _)) => self.remove_breakpoint(addr),
+                    None => false,
+                };
+                response.push_str(if ok { "OK" } else { "E01" });
+            }
+            b'H' => response.push_str("OK"),
+            b'q' if args.starts_with(b"Supported") => {
+                response.push_str("PacketSize=");
+                response.push_hex((PACKET_SIZE >> 8) as u8);
+                response.push_hex(PACKET_SIZE as u8);
+            }
+            b'q' if args == b"Attached" => response.push_str("1"),
+            b'D' => {
+                response.push_str("OK");
+                return Action::Detach;
+            }
+            b'k' => return Action::Detach,
+            // unsupported, answered with an empty packet
+            _ => (),
+        }
+
+        Action::Reply
+    }
+}
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(c) = serial::readchar() {
+            return c;
+        }
+    }
+}
+
+/// Receives a packet into `buf` and returns its length.
+fn receive(buf: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        while read_byte() != b'$' {}
+
+        let mut len = 0;
+        loop {
+            match read_byte() {
+                b'#' => break,
+                c if len < PACKET_SIZE => {
+                    buf[len] = c;
+                    len += 1;
+                }
+                _ => (),
+            }
+        }
+
+        let high = hex_digit(read_byte());
+        let low = hex_digit(read_byte());
+        match (high, low) {
+            (Some(h), Some(l)) if (h << 4) | l == checksum(&buf[..len]) => {
+                serial::writechar(b'+');
+                return len;
+            }
+            _ => serial::writechar(b'-'),
+        }
+    }
+}
+
+fn send(data: &[u8]) {
+    let sum = checksum(data);
+    loop {
+        serial::writechar(b'$');
+        for &b in data {
+            serial::writechar(b);
+        }
+        serial::writechar(b'#');
+        serial::writechar(hex_char(sum >> 4));
+        serial::writechar(hex_char(sum & 0xf));
+
+        if read_byte() == b'+' {
+            return;
+        }
+    }
+}
+
+/// Enables debug exceptions so that breakpoints and single stepping end up
+/// in the stub.
+pub fn init() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        // Clear the OS lock, debug exceptions are not taken while it is set.
+        asm!("msr oslar_el1, xzr" :::: "volatile");
+        STUB.enabled = true;
+    }
+
+    modify_mdscr(MDSCR_KDE, 0);
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("msr daifclr, #8" :::: "volatile");
+    }
+}
+
+/// Stops execution and hands control over to GDB.
+pub fn breakpoint() {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        asm!("brk #0" :::: "volatile");
+    }
+}
+
+/// Called from the synchronous exception handler. Returns false if the
+/// exception was not a debug exception meant for the stub.
+pub fn handle_exception(frame: &mut ExceptionFrame, esr: Esr) -> bool {
+    let stub = unsafe { &mut STUB };
+    if !stub.enabled {
+        return false;
+    }
+
+    match esr.class() {
+        ExceptionClass::Brk
+        | ExceptionClass::BreakpointSameEl
+        | ExceptionClass::SoftwareStepSameEl => (),
+        _ => return false,
+    }
+
+    modify_mdscr(0, MDSCR_SS);
+    frame.spsr &= !SPSR_SS;
+
+    // A `brk` compiled into the program has to be stepped over on resume,
+    // unlike the ones inserted by GDB which are removed before continuing.
+    let stop_pc = frame.elr;
+    let skip = esr.class() == ExceptionClass::Brk && !stub.is_breakpoint(stop_pc);
+
+    if stub.attached {
+        send(b"S05");
+    }
+
+    let mut packet = [0; PACKET_SIZE];
+    loop {
+        let len = receive(&mut packet);
+        stub.attached = true;
+
+        let mut response = Packet::new();
+        let step = match stub.handle_packet(&packet[..len], frame, &mut response) {
+            Action::Reply => {
+                send(response.as_bytes());
+                continue;
+            }
+            Action::Resume { step } => step,
+            Action::Detach => {
+                if response.len > 0 {
+                    send(response.as_bytes());
+                }
+                stub.remove_all_breakpoints();
+                stub.attached = false;
+                false
+            }
+        };
+
+        if skip && frame.elr == stop_pc {
+            frame.elr += 4;
+        }
+
+        if step {
+            modify_mdscr(MDSCR_SS, 0);
+            frame.spsr |= SPSR_SS;
+        }
+
+        // debug exceptions have to be unmasked for breakpoints and stepping
+        frame.spsr &= !SPSR_D;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> ExceptionFrame {
+        let mut frame = ExceptionFrame {
+            regs: [0; 31],
+            elr: 0x8_1000,
+            spsr: 0x3c5,
+            sp: 0x7_ff00,
+            fp: Default::default(),
+        };
+        for (i, reg) in frame.regs.iter_mut().enumerate() {
+            *reg = i as u64;
+        }
+        frame
+    }
+
+    fn handle(stub: &mut Stub, frame: &mut ExceptionFrame, packet: &[u8]) -> (Action, String) {
+        let mut response = Packet::new();
+        let action = stub.handle_packet(packet, frame, &mut response);
+        (
+            action,
+            String::from_utf8(response.as_bytes().to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn hex_parsing() {
+        assert_eq!(parse_hex(b"80a0"), Some(0x80a0));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"x"), None);
+        assert_eq!(parse_le(b"0010"), Some(0x1000));
+        assert_eq!(parse_range(b"8000,4"), Some((0x8000, 4)));
+        assert_eq!(checksum(b"OK"), 0x9a);
+    }
+
+    #[test]
+    fn registers() {
+        let mut stub = Stub::new();
+        let mut frame = frame();
+
+        let (_, regs) = handle(&mut stub, &mut frame, b"g");
+        assert_eq!(regs.len(), (33 * 8 + 4) * 2);
+        assert_eq!(&regs[16..32], "0100000000000000");
+        assert_eq!(&regs[32 * 16..33 * 16], "0010080000000000");
+        assert_eq!(&regs[33 * 16..], "c5030000");
+
+        assert_eq!(handle(&mut stub, &mut frame, b"p20").1, "0010080000000000");
+        assert_eq!(handle(&mut stub, &mut frame, b"p40").1, "E01");
+
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"P1e=0800080000000000").1,
+            "OK"
+        );
+        assert_eq!(frame.regs[30], 0x8_0008);
+
+        // writing back what was read leaves the frame untouched
+        let before = frame.regs;
+        let g = format!("G{}", handle(&mut stub, &mut frame, b"g").1);
+        assert_eq!(handle(&mut stub, &mut frame, g.as_bytes()).1, "OK");
+        assert_eq!(frame.regs, before);
+        assert_eq!(frame.elr, 0x8_1000);
+        assert_eq!(frame.spsr, 0x3c5);
+    }
+
+    #[test]
+    fn memory() {
+        let mut stub = Stub::new();
+        let mut frame = frame();
+        let mut data = [0x12u8, 0x34, 0x56, 0x78];
+        let addr = data.as_mut_ptr() as u64;
+
+        let read = format!("m{:x},4", addr);
+        assert_eq!(handle(&mut stub, &mut frame, read.as_bytes()).1, "12345678");
+
+        let write = format!("M{:x},2:abcd", addr + 1);
+        assert_eq!(handle(&mut stub, &mut frame, write.as_bytes()).1, "OK");
+        assert_eq!(data, [0x12, 0xab, 0xcd, 0x78]);
+
+        let bad = format!("M{:x},2:ab", addr);
+        assert_eq!(handle(&mut stub, &mut frame, bad.as_bytes()).1, "E01");
+
+        // nothing is written unless all of it parses
+        let bad = format!("M{:x},2:99zz", addr);
+        assert_eq!(handle(&mut stub, &mut frame, bad.as_bytes()).1, "E01");
+        assert_eq!(data, [0x12, 0xab, 0xcd, 0x78]);
+
+        // would wrap around the address space
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"mffffffffffffffff,2").1,
+            "E01"
+        );
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"Mffffffffffffffff,2:0000").1,
+            "E01"
+        );
+        assert_eq!(handle(&mut stub, &mut frame, b"M").1, "E01");
+        assert_eq!(handle(&mut stub, &mut frame, b"P").1, "E01");
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut stub = Stub::new();
+        let mut frame = frame();
+        let mut code = [0xd503_201fu32; 2]; // nop
+        let addr = code.as_mut_ptr() as u64;
+
+        let insert = format!("Z0,{:x},4", addr + 4);
+        assert_eq!(handle(&mut stub, &mut frame, insert.as_bytes()).1, "OK");
+        assert!(stub.is_breakpoint(addr + 4));
+        assert_eq!(code[1], BRK);
+
+        let remove = format!("z0,{:x},4", addr + 4);
+        assert_eq!(handle(&mut stub, &mut frame, remove.as_bytes()).1, "OK");
+        assert_eq!(code[1], 0xd503_201f);
+        assert_eq!(handle(&mut stub, &mut frame, remove.as_bytes()).1, "E01");
+
+        // hardware breakpoints are not supported
+        assert_eq!(handle(&mut stub, &mut frame, b"Z1,80000,4").1, "");
+    }
+
+    #[test]
+    fn resume() {
+        let mut stub = Stub::new();
+        let mut frame = frame();
+
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"c").0,
+            Action::Resume { step: false }
+        );
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"s80000").0,
+            Action::Resume { step: true }
+        );
+        assert_eq!(frame.elr, 0x8_0000);
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"D"),
+            (Action::Detach, "OK".to_owned())
+        );
+        assert_eq!(handle(&mut stub, &mut frame, b"?").1, "S05");
+        assert_eq!(
+            handle(&mut stub, &mut frame, b"qSupported:swbreak+").1,
+            "PacketSize=0400"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Driver for the 54 GPIO pins of the BCM2837.
+//!
+//! Every pin has a function, either input, output or one of six alternate
+//! functions that connect it to a peripheral, e.g. ALT0 for the PL011 on
+//! GPIO14/15 (BCM2835 ARM Peripherals, section 6). Inputs can have a pull
+//! up or down and report edges and levels, which call a callback registered
+//! with `on_event` from the interrupt handler.
+use crate::cpu::interrupt::{self, Mutex};
+use crate::cpu::irq;
+use crate::prelude::*;
+use crate::timer;
+use core::cell::RefCell;
+
+const GPIO_BASE: u32 = mem_constants::MMIO_BASE + 0x20_0000;
+
+const GPFSEL0: u32 = 0x00;
+const GPSET0: u32 = 0x1c;
+const GPCLR0: u32 = 0x28;
+const GPLEV0: u32 = 0x34;
+/// Event detect status, a one is written to clear a pin's event.
+const GPEDS0: u32 = 0x40;
+const GPREN0: u32 = 0x4c;
+const GPFEN0: u32 = 0x58;
+const GPHEN0: u32 = 0x64;
+const GPLEN0: u32 = 0x70;
+/// Controls actuation of the pull up/down of all pins.
+const GPPUD: u32 = 0x94;
+/// Clocks the control signal of `GPPUD` into specific pins.
+const GPPUDCLK0: u32 = 0x98;
+
+/// GPU interrupts gpio_int[0-2], for events on pins 0-27, 28-45 and 46-53.
+const GPIO_IRQS: [usize; 3] = [49, 50, 51];
+
+/// The pull up/down control signal needs 150 cycles to set up and to be
+/// clocked into the pins, a couple of us is plenty.
+const PULL_DELAY_US: u64 = 5;
+
+pub const PINS: u8 = 54;
+
+/// Called from the interrupt handler with the pin that saw an event.
+pub type Callback = fn(pin: u8);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Input,
+    Output,
+    Alt0,
+    Alt1,
+    Alt2,
+    Alt3,
+    Alt4,
+    Alt5,
+}
+
+impl Function {
+    fn bits(self) -> u32 {
+        match self {
+            Function::Input => 0b000,
+            Function::Output => 0b001,
+            Function::Alt0 => 0b100,
+            Function::Alt1 => 0b101,
+            Function::Alt2 => 0b110,
+            Function::Alt3 => 0b111,
+            Function::Alt4 => 0b011,
+            Function::Alt5 => 0b010,
+        }
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0b111 {
+            0b000 => Function::Input,
+            0b001 => Function::Output,
+            0b100 => Function::Alt0,
+            0b101 => Function::Alt1,
+            0b110 => Function::Alt2,
+            0b111 => Function::Alt3,
+            0b011 => Function::Alt4,
+            _ => Function::Alt5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pull {
+    None,
+    Down,
+    Up,
+}
+
+/// What an input reports as an event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    RisingEdge,
+    FallingEdge,
+    /// Reported for as long as the pin is high, the callback has to clear
+    /// the cause or disable the trigger.
+    High,
+    /// Like `High`, while the pin is low.
+    Low,
+}
+
+impl Trigger {
+    const ALL: [Trigger; 4] = [
+        Trigger::RisingEdge,
+        Trigger::FallingEdge,
+        Trigger::High,
+        Trigger::Low,
+    ];
+
+    fn register(self) -> u32 {
+        match self {
+            Trigger::RisingEdge => GPREN0,
+            Trigger::FallingEdge => GPFEN0,
+            Trigger::High => GPHEN0,
+            Trigger::Low => GPLEN0,
+        }
+    }
+}
+
+static CALLBACKS: Mutex<RefCell<[Option<Callback>; PINS as usize]>> =
+    Mutex::new(RefCell::new([None; PINS as usize]));
+
+fn register(offset: u32) -> *mut u32 {
+    (GPIO_BASE + offset) as *mut u32
+}
+
+/// Register of a pin in a bank of registers with one bit per pin, and the
+/// pin's bit.
+fn bit(base: u32, pin: u8) -> (*mut u32, u32) {
+    assert!(pin < PINS, "GPIO pin {} out of range", pin);
+    (register(base + u32::from(pin / 32) * 4), 1 << (pin % 32))
+}
+
+/// GPU interrupt raised for events on a pin, the pins are grouped as in the
+/// pinctrl-bcm2835 driver of Linux rather than by register bank.
+fn irq(pin: u8) -> usize {
+    assert!(pin < PINS, "GPIO pin {} out of range", pin);
+    match pin {
+        0..=27 => GPIO_IRQS[0],
+        28..=45 => GPIO_IRQS[1],
+        _ => GPIO_IRQS[2],
+    }
+}
+
+/// Register and bit offset of a pin's three function select bits.
+fn function_field(pin: u8) -> (u32, u32) {
+    assert!(pin < PINS, "GPIO pin {} out of range", pin);
+    (GPFSEL0 + u32::from(pin / 10) * 4, u32::from(pin % 10) * 3)
+}
+
+pub fn set_function(pin: u8, function: Function) {
+    let (offset, shift) = function_field(pin);
+    let fsel = register(offset);
+
+    // other pins share the register
+    interrupt::free(|_| unsafe {
+        let value = fsel.read_volatile() & !(0b111 << shift);
+        fsel.write_volatile(value | function.bits() << shift);
+    });
+}
+
+pub fn function(pin: u8) -> Function {
+    let (offset, shift) = function_field(pin);
+    Function::from_bits(unsafe { register(offset).read_volatile() } >> shift)
+}
+
+/// Drives an output high.
+pub fn set(pin: u8) {
+    let (reg, bit) = bit(GPSET0, pin);
+    unsafe { reg.write_volatile(bit) }
+}
+
+/// Drives an output low.
+pub fn clear(pin: u8) {
+    let (reg, bit) = bit(GPCLR0, pin);
+    unsafe { reg.write_volatile(bit) }
+}
+
+pub fn write(pin: u8, high: bool) {
+    if high {
+        set(pin)
+    } else {
+        clear(pin)
+    }
+}
+
+/// Level of a pin, whatever its function.
+pub fn read(pin: u8) -> bool {
+    let (reg, bit) = bit(GPLEV0, pin);
+    unsafe { reg.read_volatile() & bit != 0 }
+}
+
+/// Sets the pull up/down of several pins at once, they keep it until it is
+/// changed or the power is cut.
+pub fn set_pulls(pins: &[u8], pull: Pull) {
+    let mut clocks = [0u32; 2];
+    for &pin in pins {
+        assert!(pin < PINS, "GPIO pin {} out of range", pin);
+        clocks[usize::from(pin / 32)] |= 1 << (pin % 32);
+    }
+
+    let control = match pull {
+        Pull::None => 0b00,
+        Pull::Down => 0b01,
+        Pull::Up => 0b10,
+    };
+
+    interrupt::free(|_| unsafe {
+        // set up the control signal, clock it into the pins, then remove the
+        // signal and the clock
+        register(GPPUD).write_volatile(control);
+        timer::delay_us(PULL_DELAY_US);
+
+        register(GPPUDCLK0).write_volatile(clocks[0]);
+        register(GPPUDCLK0 + 4).write_volatile(clocks[1]);
+        timer::delay_us(PULL_DELAY_US);
+
+        register(GPPUD).write_volatile(0);
+        register(GPPUDCLK0).write_volatile(0);
+        register(GPPUDCLK0 + 4).write_volatile(0);
+    });
+}
+
+pub fn set_pull(pin: u8, pull: Pull) {
+    set_pulls(&[pin], pull)
+}
+
+/// Starts reporting `trigger` on `pin`, as an event for `on_event`.
+pub fn enable_trigger(pin: u8, trigger: Trigger) {
+    let (reg, bit) = bit(trigger.register(), pin);
+    interrupt::free(|_| unsafe { reg.write_volatile(reg.read_volatile() | bit) });
+}
+
+pub fn disable_trigger(pin: u8, trigger: Trigger) {
+    let (reg, bit) = bit(trigger.register(), pin);
+    interrupt::free(|_| unsafe { reg.write_volatile(reg.read_volatile() & !bit) });
+}
+
+/// Returns true if an event was seen on `pin` and clears it.
+pub fn take_event(pin: u8) -> bool {
+    let (reg, bit) = bit(GPEDS0, pin);
+    unsafe {
+        let seen = reg.read_volatile() & bit != 0;
+        if seen {
+            reg.write_volatile(bit);
+        }
+        seen
+    }
+}
+
+/// Calls `callback` from the interrupt handler on `trigger` events of `pin`.
+/// Triggers enabled before stay enabled and call the same callback, so both
+/// edges can be reported.
+pub fn on_event(pin: u8, trigger: Trigger, callback: Callback) {
+    assert!(pin < PINS, "GPIO pin {} out of range", pin);
+
+    interrupt::free(|cs| CALLBACKS.borrow(cs).borrow_mut()[usize::from(pin)] = Some(callback));
+    take_event(pin);
+    enable_trigger(pin, trigger);
+
+    let irq = irq(pin);
+    if !irq::is_registered(irq) {
+        irq::register(irq, handle_interrupt);
+    }
+}
+
+/// Disables every trigger of `pin` and removes its callback.
+pub fn remove_callback(pin: u8) {
+    disable_triggers(pin);
+    take_event(pin);
+
+    interrupt::free(|cs| CALLBACKS.borrow(cs).borrow_mut()[usize::from(pin)] = None);
+}
+
+/// Pins of `bank` that exist, the second bank has only 22.
+fn bank_mask(bank: u32) -> u32 {
+    let pins = (u32::from(PINS) - bank * 32).min(32);
+    u32::max_value() >> (32 - pins)
+}
+
+fn handle_interrupt() {
+    for bank in 0..2 {
+        let status = register(GPEDS0 + bank * 4);
+        let pending = unsafe { status.read_volatile() } & bank_mask(bank);
+
+        for i in (0..32).filter(|i| pending & 1 << i != 0) {
+            unsafe {
+                status.write_volatile(1 << i);
+            }
+
+            let pin = (bank * 32 + i) as u8;
+            match interrupt::free(|cs| CALLBACKS.borrow(cs).borrow()[usize::from(pin)]) {
+                Some(callback) => callback(pin),
+                // would raise the interrupt over and over
+                None => disable_triggers(pin),
+            }
+        }
+    }
+}
+
+fn disable_triggers(pin: u8) {
+    for &trigger in Trigger::ALL.iter() {
+        disable_trigger(pin, trigger);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn function_select_fields() {
+        assert_eq!(function_field(0), (GPFSEL0, 0));
+        assert_eq!(function_field(14), (GPFSEL0 + 4, 12));
+        assert_eq!(function_field(15), (GPFSEL0 + 4, 15));
+        assert_eq!(function_field(53), (GPFSEL0 + 20, 9));
+
+        let functions = [
+            Function::Input,
+            Function::Output,
+            Function::Alt0,
+            Function::Alt1,
+            Function::Alt2,
+            Function::Alt3,
+            Function::Alt4,
+            Function::Alt5,
+        ];
+        for &function in functions.iter() {
+            assert_eq!(Function::from_bits(function.bits() | 0b1000), function);
+        }
+    }
+
+    #[test]
+    fn pin_bits() {
+        assert_eq!(bit(GPSET0, 3), (register(GPSET0), 1 << 3));
+        assert_eq!(bit(GPSET0, 35), (register(GPSET0 + 4), 1 << 3));
+    }
+
+    #[test]
+    fn irq_lines() {
+        assert_eq!(irq(0), 49);
+        assert_eq!(irq(27), 49);
+        assert_eq!(irq(28), 50);
+        assert_eq!(irq(31), 50);
+        assert_eq!(irq(45), 50);
+        assert_eq!(irq(46), 51);
+        assert_eq!(irq(53), 51);
+    }
+
+    #[test]
+    fn bank_masks() {
+        assert_eq!(bank_mask(0), 0xffff_ffff);
+        assert_eq!(bank_mask(1), 0x003f_ffff);
+    }
+
+    #[test]
+    #[should_panic]
+    fn pin_out_of_range() {
+        bit(GPLEV0, PINS);
+    }
+}
@@ -1,7 +1,14 @@
+pub mod font;
 pub mod mailbox;
+pub mod terminal;
 use self::mailbox::{FrameBuffer, MailboxPropertyBufferBuilder, Point, Size};
+use crate::cpu::interrupt::{self, Mutex};
+use crate::logger::{debug, info};
 use crate::memory::{Allocator, Layout, MB};
 use crate::prelude::*;
+use core::cell::Cell;
+
+static SCREEN: Mutex<Cell<Option<Screen>>> = Mutex::new(Cell::new(None));
 
 pub struct Color {
     alpha: u8,
@@ -35,6 +42,24 @@ impl Color {
         green: 0,
         blue: 255,
     };
+    pub const YELLOW: Color = Color {
+        alpha: 0,
+        red: 255,
+        green: 255,
+        blue: 0,
+    };
+    pub const GRAY: Color = Color {
+        alpha: 0,
+        red: 128,
+        green: 128,
+        blue: 128,
+    };
+    pub const WHITE: Color = Color {
+        alpha: 0,
+        red: 255,
+        green: 255,
+        blue: 255,
+    };
 
     pub fn new(red: u8, green: u8, blue: u8, alpha: u8) -> Color {
         Color {
@@ -45,6 +70,10 @@ impl Color {
         }
     }
 
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        (self.red, self.green, self.blue)
+    }
+
     fn interpolate(&self, color_b: &Color, percent: f64) -> Color {
         Color {
             red: ((1.0 - percent) * f64::from(color_b.red) + percent * f64::from(self.red)) as u8,
@@ -78,6 +107,72 @@ impl Into<u32> for &Color {
     }
 }
 
+/// The frame buffer that is on screen.
+#[derive(Debug, Clone, Copy)]
+pub struct Screen {
+    pub pointer: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+}
+
+impl Screen {
+    /// Reads the pixel at `x`, `y` back from the frame buffer.
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        let rd = (self.pointer + x * 4 + y * self.pitch) as *const u32;
+        unsafe { Color::from(rd.read_volatile()) }
+    }
+
+    pub fn set_pixel(&self, x: u32, y: u32, color: &Color) {
+        if x < self.width && y < self.height {
+            let wr = (self.pointer + x * 4 + y * self.pitch) as *mut u32;
+            unsafe {
+                wr.write_volatile(color.into());
+            }
+        }
+    }
+
+    /// Draws `text` with its top left corner at `x`, `y`, clipped to the
+    /// screen.
+    pub fn draw_text(&self, x: u32, y: u32, text: &str, fg: &Color, bg: &Color) {
+        for (i, c) in text.chars().enumerate() {
+            let ox = x + i as u32 * font::GLYPH_WIDTH;
+            if ox >= self.width {
+                break;
+            }
+
+            font::draw_char(c, |gx, gy, set| {
+                self.set_pixel(ox + gx, y + gy, if set { fg } else { bg })
+            });
+        }
+    }
+
+    /// Moves everything up by `rows` pixels and clears the rows at the
+    /// bottom.
+    pub fn scroll_up(&self, rows: u32, color: &Color) {
+        let rows = rows.min(self.height);
+        let moved = ((self.height - rows) * self.pitch) as usize;
+        unsafe {
+            core::ptr::copy(
+                (self.pointer + rows * self.pitch) as *const u8,
+                self.pointer as *mut u8,
+                moved,
+            );
+        }
+
+        for y in self.height - rows..self.height {
+            for x in 0..self.width {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+/// The frame buffer set up by `init`, if any.
+pub fn screen() -> Option<Screen> {
+    interrupt::free(|cs| SCREEN.borrow(cs).get())
+}
+
 pub struct Gpu {
     frame_buffer: FrameBuffer,
     pitch: u32,
@@ -184,8 +279,8 @@ impl Gpu {
 }
 
 pub fn init(width: u32, height: u32, allocator: &dyn Allocator) -> Result<Gpu, SalmiakError> {
-    sprintln!("initializing GPU...");
-    sprintln!("* creating {}x{} framebuffer...", width, height);
+    info!("initializing GPU...");
+    debug!("creating {}x{} framebuffer...", width, height);
 
     let mut pitch = 0;
     let mut frame_buffer: FrameBuffer = Default::default();
@@ -228,43 +323,47 @@ pub fn init(width: u32, height: u32, allocator: &dyn Allocator) -> Result<Gpu, S
         .into());
     }
 
-    sprintln!(
+    debug!(
         "    SetPhys {}x{}",
-        physical_size.width,
-        physical_size.height
+        physical_size.width, physical_size.height
     );
-    sprintln!("    SetVirt {}x{}", virtual_size.width, virtual_size.height);
-    sprintln!(
+    debug!("    SetVirt {}x{}", virtual_size.width, virtual_size.height);
+    debug!(
         "    SetVirtOffset {}x{}",
-        virtual_offset.x,
-        virtual_offset.y
+        virtual_offset.x, virtual_offset.y
     );
-    sprintln!("    SetBufferDepth {}", buffer_depth);
-    sprintln!("    SetPixelOrder {}", pixel_order);
+    debug!("    SetBufferDepth {}", buffer_depth);
+    debug!("    SetPixelOrder {}", pixel_order);
 
-    sprintln!(
+    debug!(
         "    GetPhys {}x{}",
-        get_physical_size.width,
-        get_physical_size.height
+        get_physical_size.width, get_physical_size.height
     );
-    sprintln!(
+    debug!(
         "    GetVirt {}x{}",
-        get_virtual_size.width,
-        get_virtual_size.height
+        get_virtual_size.width, get_virtual_size.height
     );
-    sprintln!(
+    debug!(
         "    GetVirtOffset {}x{}",
-        get_virtual_offset.x,
-        get_virtual_offset.y
+        get_virtual_offset.x, get_virtual_offset.y
     );
-    sprintln!("    GetBufferDepth {}", get_buffer_depth);
-    sprintln!("    GetPixelOrder {}", get_pixel_order);
-    sprintln!("    Frame Buffer Pointer {:x}", frame_buffer.pointer);
-    sprintln!(
+    debug!("    GetBufferDepth {}", get_buffer_depth);
+    debug!("    GetPixelOrder {}", get_pixel_order);
+    debug!("    Frame Buffer Pointer {:x}", frame_buffer.pointer);
+    debug!(
         "    Frame Buffer Size {} MB",
         frame_buffer.size as usize / MB
     );
 
-    sprintln!("done!");
+    interrupt::free(|cs| {
+        SCREEN.borrow(cs).set(Some(Screen {
+            pointer: frame_buffer.pointer,
+            width: physical_size.width,
+            height: physical_size.height,
+            pitch,
+        }))
+    });
+
+    info!("GPU initialized");
     Ok(Gpu::new(frame_buffer, physical_size, pitch, allocator))
 }
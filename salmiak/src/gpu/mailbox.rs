@@ -47,6 +47,8 @@ mod property_buffer {
 // different clock constants
 pub mod clock {
     pub const UART: u32 = 0x2;
+    pub const ARM: u32 = 0x3;
+    pub const CORE: u32 = 0x4;
 }
 
 // These structs mainly exist to make sense to whoever is using the interface
@@ -0,0 +1,372 @@
+//! Shows the screen on an ANSI terminal, for playing over serial without a
+//! display.
+//!
+//! A `Grid` of character cells is drawn with cursor positioning and 256
+//! colour or truecolor escapes. `Terminal` remembers what it has drawn and
+//! only sends the cells that changed since the last frame, which at 115200
+//! baud is the difference between a few frames per second and one every few
+//! seconds. A `Grid` can be filled with text directly or with a downscaled
+//! view of the screen, two pixels per cell using the upper half block.
+//!
+//! `enable` turns on the view of the screen, `GameLoop` then draws it after
+//! every frame. Anything else printed to the console scrolls or overwrites
+//! the view, `invalidate` draws it all again.
+use super::Screen;
+use crate::cpu::interrupt::{self, Mutex};
+use crate::prelude::*;
+use crate::serial::SerialWriter;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+/// Size of the view, fits an 80x24 terminal with a line to spare below.
+pub const DEFAULT_COLUMNS: u32 = 80;
+pub const DEFAULT_ROWS: u32 = 23;
+
+/// Upper half block, the foreground colour is the top pixel and the
+/// background colour the bottom one.
+const HALF_BLOCK: char = '\u{2580}';
+
+pub type Rgb = (u8, u8, u8);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    /// The xterm 256 colour palette, for terminals without truecolor.
+    Palette256,
+    TrueColor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub ch: char,
+    pub fg: Rgb,
+    pub bg: Rgb,
+}
+
+impl Cell {
+    pub const BLANK: Cell = Cell {
+        ch: ' ',
+        fg: (255, 255, 255),
+        bg: (0, 0, 0),
+    };
+}
+
+/// Characters to draw on a terminal.
+#[derive(Debug, Clone)]
+pub struct Grid {
+    columns: u32,
+    rows: u32,
+    cells: Vec<Cell>,
+}
+
+impl Grid {
+    pub fn new(columns: u32, rows: u32) -> Self {
+        let mut cells = Vec::new();
+        cells.resize((columns * rows) as usize, Cell::BLANK);
+        Grid {
+            columns,
+            rows,
+            cells,
+        }
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Option<Cell> {
+        if x < self.columns && y < self.rows {
+            Some(self.cells[(y * self.columns + x) as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Cells outside of the grid are ignored.
+    pub fn set(&mut self, x: u32, y: u32, cell: Cell) {
+        if x < self.columns && y < self.rows {
+            self.cells[(y * self.columns + x) as usize] = cell;
+        }
+    }
+
+    pub fn clear(&mut self, bg: Rgb) {
+        for cell in self.cells.iter_mut() {
+            *cell = Cell { bg, ..Cell::BLANK };
+        }
+    }
+
+    /// Writes `text` from `x`, `y` on, cut off at the right edge.
+    pub fn put_str(&mut self, x: u32, y: u32, text: &str, fg: Rgb, bg: Rgb) {
+        for (i, ch) in text.chars().enumerate() {
+            self.set(x + i as u32, y, Cell { ch, fg, bg });
+        }
+    }
+
+    /// Fills the grid with a `width` by `height` image, sampling `pixel` at
+    /// the middle of each half cell.
+    pub fn downscale<F: Fn(u32, u32) -> Rgb>(&mut self, width: u32, height: u32, pixel: F) {
+        let (columns, rows) = (self.columns, self.rows);
+        let sample = |column: u32, half_row: u32| {
+            let x = (2 * column + 1) * width / (2 * columns);
+            let y = (2 * half_row + 1) * height / (4 * rows);
+            pixel(x, y)
+        };
+
+        for y in 0..self.rows {
+            for x in 0..self.columns {
+                let cell = Cell {
+                    ch: HALF_BLOCK,
+                    fg: sample(x, 2 * y),
+                    bg: sample(x, 2 * y + 1),
+                };
+                self.cells[(y * self.columns + x) as usize] = cell;
+            }
+        }
+    }
+
+    /// Fills the grid with a downscaled view of `screen`.
+    pub fn draw_screen(&mut self, screen: &Screen) {
+        self.downscale(screen.width, screen.height, |x, y| screen.pixel(x, y).rgb());
+    }
+}
+
+/// Nearest colour of the xterm palette, from the 6x6x6 cube or the 24 grays.
+pub fn palette_index((red, green, blue): Rgb) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    let level = |c: u8| match c {
+        0..=47 => 0,
+        48..=114 => 1,
+        _ => (c - 35) / 40,
+    };
+    let distance = |(r, g, b): Rgb| {
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(r, red) + d(g, green) + d(b, blue)
+    };
+
+    let (r, g, b) = (level(red), level(green), level(blue));
+    let cube = (LEVELS[r as usize], LEVELS[g as usize], LEVELS[b as usize]);
+
+    let average = (u32::from(red) + u32::from(green) + u32::from(blue)) / 3;
+    let gray = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray_level = 8 + 10 * gray;
+
+    if distance((gray_level, gray_level, gray_level)) < distance(cube) {
+        232 + gray
+    } else {
+        16 + 36 * r + 6 * g + b
+    }
+}
+
+/// Draws grids on a terminal, sending only what changed.
+#[derive(Debug)]
+pub struct Terminal {
+    mode: ColorMode,
+    /// What the terminal shows, `None` where it is not known.
+    shown: Vec<Option<Cell>>,
+    columns: u32,
+    rows: u32,
+}
+
+impl Terminal {
+    pub fn new(mode: ColorMode) -> Self {
+        Terminal {
+            mode,
+            shown: Vec::new(),
+            columns: 0,
+            rows: 0,
+        }
+    }
+
+    /// Makes the next `draw` send everything.
+    pub fn invalidate(&mut self) {
+        self.shown.clear();
+    }
+
+    fn write_color<W: Write>(&self, out: &mut W, layer: u8, (r, g, b): Rgb) -> fmt::Result {
+        match self.mode {
+            ColorMode::Palette256 => write!(out, "\x1b[{};5;{}m", layer, palette_index((r, g, b))),
+            ColorMode::TrueColor => write!(out, "\x1b[{};2;{};{};{}m", layer, r, g, b),
+        }
+    }
+
+    /// Draws `grid` at the top left of the terminal and leaves the cursor
+    /// below it.
+    pub fn draw<W: Write>(&mut self, grid: &Grid, out: &mut W) -> fmt::Result {
+        if self.shown.is_empty() || grid.columns != self.columns || grid.rows != self.rows {
+            self.columns = grid.columns;
+            self.rows = grid.rows;
+            self.shown.clear();
+            self.shown.resize((grid.columns * grid.rows) as usize, None);
+            // hide the cursor and clear the screen
+            out.write_str("\x1b[?25l\x1b[0m\x1b[2J")?;
+        }
+
+        let mut cursor = None;
+        let mut colors: (Option<Rgb>, Option<Rgb>) = (None, None);
+
+        for y in 0..self.rows {
+            for x in 0..self.columns {
+                let i = (y * self.columns + x) as usize;
+                let cell = grid.cells[i];
+                if self.shown[i] == Some(cell) {
+                    continue;
+                }
+
+                if cursor != Some((x, y)) {
+                    write!(out, "\x1b[{};{}H", y + 1, x + 1)?;
+                }
+                if colors.0 != Some(cell.fg) {
+                    self.write_color(out, 38, cell.fg)?;
+                }
+                if colors.1 != Some(cell.bg) {
+                    self.write_color(out, 48, cell.bg)?;
+                }
+                out.write_char(cell.ch)?;
+
+                self.shown[i] = Some(cell);
+                colors = (Some(cell.fg), Some(cell.bg));
+                // after the last column terminals differ in where the cursor is
+                cursor = if x + 1 < self.columns {
+                    Some((x + 1, y))
+                } else {
+                    None
+                };
+            }
+        }
+
+        if colors != (None, None) {
+            write!(out, "\x1b[0m\x1b[{};1H", self.rows + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Shows the cursor again, resets the colours and forgets what is shown.
+    pub fn leave<W: Write>(&mut self, out: &mut W) -> fmt::Result {
+        self.invalidate();
+        write!(out, "\x1b[0m\x1b[?25h\x1b[{};1H", self.rows + 1)
+    }
+}
+
+static VIEW: Mutex<RefCell<Option<(Terminal, Grid)>>> = Mutex::new(RefCell::new(None));
+
+/// Starts showing the screen on the serial console, `columns` by `rows`
+/// characters large.
+pub fn enable(mode: ColorMode, columns: u32, rows: u32) {
+    let view = (Terminal::new(mode), Grid::new(columns, rows));
+    interrupt::free(|cs| *VIEW.borrow(cs).borrow_mut() = Some(view));
+}
+
+pub fn disable() {
+    if let Some((mut terminal, _)) = interrupt::free(|cs| VIEW.borrow(cs).borrow_mut().take()) {
+        let _ = terminal.leave(&mut SerialWriter);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    interrupt::free(|cs| VIEW.borrow(cs).borrow().is_some())
+}
+
+/// Draws the whole view again on the next `present`.
+pub fn invalidate() {
+    interrupt::free(|cs| {
+        if let Some((terminal, _)) = VIEW.borrow(cs).borrow_mut().as_mut() {
+            terminal.invalidate();
+        }
+    });
+}
+
+/// Draws what changed on the screen since the last call, if enabled.
+pub fn present() {
+    let screen = match super::screen() {
+        Some(screen) => screen,
+        None => return,
+    };
+
+    // drawing waits for the serial port, so it is done outside of the
+    // critical section
+    let (mut terminal, mut grid) = match interrupt::free(|cs| VIEW.borrow(cs).borrow_mut().take()) {
+        Some(view) => view,
+        None => return,
+    };
+
+    grid.draw_screen(&screen);
+    let _ = terminal.draw(&grid, &mut SerialWriter);
+
+    interrupt::free(|cs| *VIEW.borrow(cs).borrow_mut() = Some((terminal, grid)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgb = (255, 0, 0);
+    const BLACK: Rgb = (0, 0, 0);
+
+    #[test]
+    fn palette() {
+        assert_eq!(palette_index((0, 0, 0)), 16);
+        assert_eq!(palette_index((255, 255, 255)), 231);
+        assert_eq!(palette_index((255, 0, 0)), 196);
+        assert_eq!(palette_index((0, 95, 135)), 24);
+        assert_eq!(palette_index((128, 128, 128)), 244);
+    }
+
+    #[test]
+    fn draws_only_changes() {
+        let mut grid = Grid::new(4, 2);
+        grid.put_str(0, 0, "hi", RED, BLACK);
+
+        let mut terminal = Terminal::new(ColorMode::TrueColor);
+        let mut out = String::new();
+        terminal.draw(&grid, &mut out).unwrap();
+        assert!(
+            out.starts_with("\x1b[?25l\x1b[0m\x1b[2J\x1b[1;1H\x1b[38;2;255;0;0m\x1b[48;2;0;0;0mhi")
+        );
+
+        let mut out = String::new();
+        terminal.draw(&grid, &mut out).unwrap();
+        assert_eq!(out, "");
+
+        grid.set(
+            2,
+            1,
+            Cell {
+                ch: 'x',
+                fg: RED,
+                bg: BLACK,
+            },
+        );
+        let mut terminal_256 = Terminal::new(ColorMode::Palette256);
+        terminal_256.draw(&grid, &mut String::new()).unwrap();
+
+        let mut out = String::new();
+        terminal.draw(&grid, &mut out).unwrap();
+        assert_eq!(
+            out,
+            "\x1b[2;3H\x1b[38;2;255;0;0m\x1b[48;2;0;0;0mx\x1b[0m\x1b[3;1H"
+        );
+
+        grid.put_str(1, 1, "yz", (255, 255, 255), BLACK);
+        let mut out = String::new();
+        terminal_256.draw(&grid, &mut out).unwrap();
+        assert_eq!(
+            out,
+            "\x1b[2;2H\x1b[38;5;231m\x1b[48;5;16myz\x1b[0m\x1b[3;1H"
+        );
+    }
+
+    #[test]
+    fn downscales() {
+        let mut grid = Grid::new(2, 1);
+        // left half red, bottom right quarter red
+        grid.downscale(40, 20, |x, y| if x < 20 || y >= 10 { RED } else { BLACK });
+
+        assert_eq!(grid.get(0, 0).map(|c| (c.fg, c.bg)), Some((RED, RED)));
+        assert_eq!(grid.get(1, 0).map(|c| (c.fg, c.bg)), Some((BLACK, RED)));
+        assert_eq!(grid.get(2, 0), None);
+    }
+}
@@ -0,0 +1,403 @@
+//! Button state for games, whatever the buttons are.
+//!
+//! Sources report presses and releases with `handle`, keys from the serial
+//! console are picked up by `update`. Once per frame `GameLoop` calls
+//! `update`, which takes a snapshot of what is held into an `InputState` and
+//! passes it to `Game::input`. Between two snapshots a source is "just
+//! pressed" if it was pressed at all, so presses shorter than a frame are not
+//! lost. Keys from a terminal are always that short, holding one down repeats
+//! the press instead.
+//!
+//! An `ActionMap` maps sources to the actions of a game, so that e.g. both
+//! `w` and the up arrow mean "up".
+use crate::cpu::interrupt::{self, Mutex};
+use crate::keyboard::{self, Key, KeyEvent, Modifiers};
+use crate::prelude::*;
+use core::cell::RefCell;
+
+/// Most sources held at the same time, further presses are ignored.
+pub const MAX_HELD: usize = 16;
+
+/// Something that can be pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// A key on the serial console, made with `Source::key` or
+    /// `Source::key_with`.
+    Key(KeySource),
+    /// A button on a GPIO pin.
+    Gpio(u8),
+    /// A button of a controller.
+    Controller { controller: u8, button: u8 },
+}
+
+impl Source {
+    /// A key pressed without ctrl or alt. Letters are made lower case and
+    /// shift is ignored, so that bindings work with shift and caps lock too.
+    pub fn key(key: Key) -> Self {
+        Source::key_with(key, Modifiers::NONE)
+    }
+
+    /// A key pressed with the ctrl and alt of `modifiers`, e.g. ctrl-a is
+    /// `Key::Char('a')` with ctrl. Shift is ignored as for `key`.
+    pub fn key_with(key: Key, modifiers: Modifiers) -> Self {
+        let key = match key {
+            Key::Char(c) => Key::Char(c.to_ascii_lowercase()),
+            key => key,
+        };
+        Source::Key(KeySource {
+            key,
+            modifiers: Modifiers {
+                shift: false,
+                ..modifiers
+            },
+        })
+    }
+}
+
+/// A key with the modifiers it has to be pressed with, see `Source::key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySource {
+    key: Key,
+    modifiers: Modifiers,
+}
+
+impl KeySource {
+    pub fn key(self) -> Key {
+        self.key
+    }
+
+    pub fn modifiers(self) -> Modifiers {
+        self.modifiers
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub source: Source,
+    pub pressed: bool,
+}
+
+impl From<KeyEvent> for InputEvent {
+    fn from(event: KeyEvent) -> Self {
+        InputEvent {
+            source: Source::key_with(event.key, event.modifiers),
+            pressed: event.pressed,
+        }
+    }
+}
+
+/// A set of up to `MAX_HELD` sources.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sources {
+    sources: [Option<Source>; MAX_HELD],
+}
+
+impl Sources {
+    const fn new() -> Self {
+        Sources {
+            sources: [None; MAX_HELD],
+        }
+    }
+
+    fn contains(&self, source: Source) -> bool {
+        self.sources.contains(&Some(source))
+    }
+
+    fn insert(&mut self, source: Source) {
+        if self.contains(source) {
+            return;
+        }
+
+        if let Some(slot) = self.sources.iter_mut().find(|s| s.is_none()) {
+            *slot = Some(source);
+        }
+    }
+
+    fn remove(&mut self, source: Source) {
+        for slot in self.sources.iter_mut() {
+            if *slot == Some(source) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn union(&self, other: &Sources) -> Sources {
+        let mut union = *self;
+        for source in other.sources.iter().flatten() {
+            union.insert(*source);
+        }
+        union
+    }
+}
+
+/// What was held during a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputState {
+    current: Sources,
+    previous: Sources,
+    pressed: Sources,
+}
+
+impl InputState {
+    pub const fn new() -> Self {
+        InputState {
+            current: Sources::new(),
+            previous: Sources::new(),
+            pressed: Sources::new(),
+        }
+    }
+
+    /// True if `source` is held or was pressed since the last frame.
+    pub fn is_pressed(&self, source: Source) -> bool {
+        self.current.contains(source)
+    }
+
+    /// True if `source` was pressed since the last frame.
+    pub fn just_pressed(&self, source: Source) -> bool {
+        self.pressed.contains(source)
+    }
+
+    /// True if `source` was pressed in the last frame but is not any more.
+    pub fn just_released(&self, source: Source) -> bool {
+        self.previous.contains(source) && !self.current.contains(source)
+    }
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        InputState::new()
+    }
+}
+
+/// Collects events between two frames.
+#[derive(Debug)]
+pub struct Input {
+    held: Sources,
+    pressed: Sources,
+    state: InputState,
+}
+
+impl Input {
+    pub const fn new() -> Self {
+        Input {
+            held: Sources::new(),
+            pressed: Sources::new(),
+            state: InputState::new(),
+        }
+    }
+
+    pub fn handle(&mut self, event: InputEvent) {
+        if event.pressed {
+            self.held.insert(event.source);
+            self.pressed.insert(event.source);
+        } else {
+            self.held.remove(event.source);
+        }
+    }
+
+    /// Ends a frame and returns its state.
+    pub fn snapshot(&mut self) -> InputState {
+        self.state = InputState {
+            current: self.held.union(&self.pressed),
+            previous: self.state.current,
+            pressed: self.pressed,
+        };
+        self.pressed = Sources::new();
+        self.state
+    }
+
+    /// The state of the last frame.
+    pub fn state(&self) -> InputState {
+        self.state
+    }
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Input::new()
+    }
+}
+
+static INPUT: Mutex<RefCell<Input>> = Mutex::new(RefCell::new(Input::new()));
+
+/// Reports a press or release, can be called from interrupt handlers.
+pub fn handle(event: InputEvent) {
+    interrupt::free(|cs| INPUT.borrow(cs).borrow_mut().handle(event));
+}
+
+/// Takes the keys typed on the serial console and ends the frame, see
+/// `Input::snapshot`.
+pub fn update() -> InputState {
+    keyboard::poll();
+    while let Some(event) = keyboard::next_event() {
+        handle(event.into());
+    }
+
+    interrupt::free(|cs| INPUT.borrow(cs).borrow_mut().snapshot())
+}
+
+/// The state of the last frame.
+pub fn state() -> InputState {
+    interrupt::free(|cs| INPUT.borrow(cs).borrow().state())
+}
+
+/// Maps sources to the actions of a game.
+#[derive(Debug, Clone)]
+pub struct ActionMap<A> {
+    bindings: Vec<(Source, A)>,
+}
+
+impl<A: Copy + PartialEq> ActionMap<A> {
+    pub fn new() -> Self {
+        ActionMap {
+            bindings: Vec::new(),
+        }
+    }
+
+    /// Makes `source` trigger `action`, in addition to other bindings.
+    pub fn bind(mut self, source: Source, action: A) -> Self {
+        self.bindings.push((source, action));
+        self
+    }
+
+    /// Removes all bindings of `action`.
+    pub fn unbind(&mut self, action: A) {
+        self.bindings.retain(|&(_, a)| a != action);
+    }
+
+    fn sources(&self, action: A) -> impl Iterator<Item = Source> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |&&(_, a)| a == action)
+            .map(|&(source, _)| source)
+    }
+
+    pub fn is_pressed(&self, state: &InputState, action: A) -> bool {
+        self.sources(action).any(|s| state.is_pressed(s))
+    }
+
+    pub fn just_pressed(&self, state: &InputState, action: A) -> bool {
+        self.sources(action).any(|s| state.just_pressed(s))
+    }
+
+    /// True if the last source held for `action` was let go.
+    pub fn just_released(&self, state: &InputState, action: A) -> bool {
+        self.sources(action).any(|s| state.just_released(s)) && !self.is_pressed(state, action)
+    }
+}
+
+impl<A: Copy + PartialEq> Default for ActionMap<A> {
+    fn default() -> Self {
+        ActionMap::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUTTON: Source = Source::Gpio(17);
+
+    fn event(source: Source, pressed: bool) -> InputEvent {
+        InputEvent { source, pressed }
+    }
+
+    #[test]
+    fn held_buttons() {
+        let mut input = Input::new();
+
+        input.handle(event(BUTTON, true));
+        let state = input.snapshot();
+        assert!(state.is_pressed(BUTTON) && state.just_pressed(BUTTON));
+
+        let state = input.snapshot();
+        assert!(state.is_pressed(BUTTON) && !state.just_pressed(BUTTON));
+
+        input.handle(event(BUTTON, false));
+        let state = input.snapshot();
+        assert!(!state.is_pressed(BUTTON) && state.just_released(BUTTON));
+
+        let state = input.snapshot();
+        assert!(!state.just_released(BUTTON));
+    }
+
+    #[test]
+    fn presses_within_a_frame() {
+        let up = Source::key(Key::Up);
+        let mut input = Input::new();
+        input.handle(event(up, true));
+        input.handle(event(up, false));
+
+        let state = input.snapshot();
+        assert!(state.is_pressed(up) && state.just_pressed(up));
+
+        // a repeated key is pressed again in every frame it repeats in
+        input.handle(event(up, true));
+        input.handle(event(up, false));
+        assert!(input.snapshot().just_pressed(up));
+
+        let state = input.snapshot();
+        assert!(!state.is_pressed(up) && state.just_released(up));
+    }
+
+    #[test]
+    fn actions() {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum Action {
+            Jump,
+            Duck,
+        }
+
+        let map = ActionMap::new()
+            .bind(Source::key(Key::Char('W')), Action::Jump)
+            .bind(BUTTON, Action::Jump)
+            .bind(Source::key(Key::Down), Action::Duck);
+
+        let mut input = Input::new();
+        input.handle(KeyEvent::press(Key::Char('w'), Default::default()).into());
+        input.handle(KeyEvent::release(Key::Char('w'), Default::default()).into());
+        input.handle(event(BUTTON, true));
+        let state = input.snapshot();
+        assert!(map.just_pressed(&state, Action::Jump));
+        assert!(!map.is_pressed(&state, Action::Duck));
+
+        // the button still holds it
+        let state = input.snapshot();
+        assert!(map.is_pressed(&state, Action::Jump));
+        assert!(!map.just_released(&state, Action::Jump));
+
+        input.handle(event(BUTTON, false));
+        assert!(map.just_released(&input.snapshot(), Action::Jump));
+    }
+
+    #[test]
+    fn key_modifiers() {
+        let ctrl = Modifiers {
+            ctrl: true,
+            ..Modifiers::NONE
+        };
+        let alt = Modifiers {
+            alt: true,
+            ..Modifiers::NONE
+        };
+        let shift = Modifiers {
+            shift: true,
+            ..Modifiers::NONE
+        };
+        let a = Source::key(Key::Char('a'));
+        let ctrl_a = Source::key_with(Key::Char('a'), ctrl);
+
+        let pressed = |key, modifiers| {
+            let mut input = Input::new();
+            input.handle(KeyEvent::press(key, modifiers).into());
+            input.handle(KeyEvent::release(key, modifiers).into());
+            input.snapshot()
+        };
+
+        assert_eq!(Source::key(Key::Char('A')), a);
+        assert!(pressed(Key::Char('A'), shift).just_pressed(a));
+        assert!(!pressed(Key::Char('a'), ctrl).just_pressed(a));
+        assert!(!pressed(Key::Char('a'), alt).just_pressed(a));
+        assert!(pressed(Key::Char('a'), ctrl).just_pressed(ctrl_a));
+    }
+}
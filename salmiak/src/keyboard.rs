@@ -0,0 +1,589 @@
+//! Keyboard input from the serial console.
+//!
+//! Terminals send arrow keys, function keys and keys with modifiers as ANSI
+//! (VT100/xterm) escape sequences. `Decoder` turns the byte stream into
+//! `KeyEvent`s, `poll` feeds it everything `shell::readchar` hands back and
+//! queues the events until they are taken with `next_event`. `input::update`
+//! polls once per frame.
+//!
+//! Terminals only report presses, so every press is followed by a release
+//! right away. Escape on its own is only known to be a key when nothing
+//! follows it within `ESCAPE_TIMEOUT`.
+use crate::cpu::interrupt::{self, Mutex};
+use crate::shell;
+use crate::timer::{Duration, Instant};
+use core::cell::RefCell;
+
+/// Number of events kept until the game takes them, later ones are dropped.
+pub const QUEUE_SIZE: usize = 32;
+
+/// Longest pause within an escape sequence.
+pub const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Most numeric parameters of a control sequence.
+const MAX_PARAMS: usize = 2;
+
+const ESC: u8 = 0x1b;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A printable character, as typed, so shift shows in the case.
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// F1 to F12.
+    F(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers {
+        shift: false,
+        alt: false,
+        ctrl: false,
+    };
+
+    /// Decodes the modifier parameter of xterm sequences, one more than a
+    /// bit mask of shift (1), alt (2) and ctrl (4).
+    fn from_param(param: u16) -> Self {
+        let mask = param.saturating_sub(1);
+        Modifiers {
+            shift: mask & 1 != 0,
+            alt: mask & 2 != 0,
+            ctrl: mask & 4 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    pub fn press(key: Key, modifiers: Modifiers) -> Self {
+        KeyEvent {
+            key,
+            pressed: true,
+            modifiers,
+        }
+    }
+
+    pub fn release(key: Key, modifiers: Modifiers) -> Self {
+        KeyEvent {
+            key,
+            pressed: false,
+            modifiers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    /// After ESC, `alt` after a second one.
+    Escape {
+        alt: bool,
+    },
+    /// After ESC [, `alt` if it was preceded by another ESC.
+    Csi {
+        alt: bool,
+    },
+    /// After ESC O, the SS3 sequences some terminals use for arrow and
+    /// function keys.
+    Ss3 {
+        alt: bool,
+    },
+    /// Within a multi-byte UTF-8 character, `left` continuation bytes to go.
+    Utf8 {
+        code: u32,
+        left: u8,
+    },
+}
+
+/// Turns bytes from a terminal into keys.
+#[derive(Debug)]
+pub struct Decoder {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    count: usize,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            count: 0,
+        }
+    }
+
+    /// True in the middle of a sequence.
+    pub fn is_pending(&self) -> bool {
+        self.state != State::Ground
+    }
+
+    /// Adds a byte, returns the key it completes. Bytes of sequences that are
+    /// not understood are dropped.
+    pub fn feed(&mut self, byte: u8) -> Option<(Key, Modifiers)> {
+        match self.state {
+            State::Ground => self.ground(byte, false),
+            State::Escape { alt } => {
+                self.state = State::Ground;
+                match byte {
+                    ESC if !alt => self.state = State::Escape { alt: true },
+                    b'[' => self.start_csi(alt),
+                    b'O' => self.state = State::Ss3 { alt },
+                    _ => return self.ground(byte, true),
+                }
+                None
+            }
+            State::Csi { alt } => self.csi(byte, alt),
+            State::Ss3 { alt } => {
+                self.state = State::Ground;
+                let modifiers = Modifiers {
+                    alt,
+                    ..Modifiers::NONE
+                };
+                final_key(byte).map(|key| (key, modifiers))
+            }
+            State::Utf8 { code, left } => {
+                if byte & 0xc0 != 0x80 {
+                    // a broken character, start over with this byte
+                    self.state = State::Ground;
+                    return self.feed(byte);
+                }
+
+                let code = code << 6 | u32::from(byte & 0x3f);
+                if left > 1 {
+                    self.state = State::Utf8 {
+                        code,
+                        left: left - 1,
+                    };
+                    None
+                } else {
+                    self.state = State::Ground;
+                    core::char::from_u32(code).map(|c| (Key::Char(c), Modifiers::NONE))
+                }
+            }
+        }
+    }
+
+    /// Ends a pending sequence when no more bytes came, returns the key it
+    /// stands for on its own. That is Escape, Alt-Escape, Alt-[ or Alt-O.
+    pub fn flush(&mut self) -> Option<(Key, Modifiers)> {
+        let alt = Modifiers {
+            alt: true,
+            ..Modifiers::NONE
+        };
+        let key = match self.state {
+            State::Escape { alt: false } => Some((Key::Escape, Modifiers::NONE)),
+            State::Escape { alt: true } => Some((Key::Escape, alt)),
+            State::Csi { .. } if self.count == 0 => Some((Key::Char('['), alt)),
+            State::Ss3 { .. } => Some((Key::Char('O'), alt)),
+            _ => None,
+        };
+
+        self.state = State::Ground;
+        key
+    }
+
+    /// Handles a byte outside of a sequence, `alt` if it followed an ESC.
+    fn ground(&mut self, byte: u8, alt: bool) -> Option<(Key, Modifiers)> {
+        let mut modifiers = Modifiers {
+            alt,
+            ..Modifiers::NONE
+        };
+
+        let key = match byte {
+            b'\r' | b'\n' => Key::Enter,
+            b'\t' => Key::Tab,
+            0x08 | 0x7f => Key::Backspace,
+            ESC => {
+                self.state = State::Escape { alt: false };
+                return None;
+            }
+            // ctrl-a to ctrl-z, except the ones above
+            0x01..=0x1a => {
+                modifiers.ctrl = true;
+                Key::Char((b'a' + byte - 1) as char)
+            }
+            0x20..=0x7e => Key::Char(byte as char),
+            0xc0..=0xdf => return self.start_utf8(byte & 0x1f, 1),
+            0xe0..=0xef => return self.start_utf8(byte & 0x0f, 2),
+            0xf0..=0xf7 => return self.start_utf8(byte & 0x07, 3),
+            _ => return None,
+        };
+
+        Some((key, modifiers))
+    }
+
+    fn start_utf8(&mut self, bits: u8, left: u8) -> Option<(Key, Modifiers)> {
+        self.state = State::Utf8 {
+            code: u32::from(bits),
+            left,
+        };
+        None
+    }
+
+    fn start_csi(&mut self, alt: bool) {
+        self.state = State::Csi { alt };
+        self.params = [0; MAX_PARAMS];
+        self.count = 0;
+    }
+
+    fn csi(&mut self, byte: u8, alt: bool) -> Option<(Key, Modifiers)> {
+        match byte {
+            b'0'..=b'9' => {
+                if self.count == 0 {
+                    self.count = 1;
+                }
+                if let Some(param) = self.params.get_mut(self.count - 1) {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(u16::from(byte - b'0'));
+                }
+                return None;
+            }
+            b';' => {
+                self.count = self.count.max(1) + 1;
+                return None;
+            }
+            // more intermediate and parameter bytes, still part of the sequence
+            0x20..=0x3f => return None,
+            _ => self.state = State::Ground,
+        }
+
+        let mut modifiers = if self.count >= 2 {
+            Modifiers::from_param(self.params[1])
+        } else {
+            Modifiers::NONE
+        };
+        modifiers.alt |= alt;
+
+        let key = match byte {
+            b'~' => tilde_key(self.params[0])?,
+            b'Z' => {
+                modifiers.shift = true;
+                Key::Tab
+            }
+            _ => final_key(byte)?,
+        };
+
+        Some((key, modifiers))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+/// Keys of sequences ending in a letter, `ESC [ A` or `ESC O A`.
+fn final_key(byte: u8) -> Option<Key> {
+    Some(match byte {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        b'P' => Key::F(1),
+        b'Q' => Key::F(2),
+        b'R' => Key::F(3),
+        b'S' => Key::F(4),
+        _ => return None,
+    })
+}
+
+/// Keys of `ESC [ n ~` sequences.
+fn tilde_key(n: u16) -> Option<Key> {
+    Some(match n {
+        1 | 7 => Key::Home,
+        2 => Key::Insert,
+        3 => Key::Delete,
+        4 | 8 => Key::End,
+        5 => Key::PageUp,
+        6 => Key::PageDown,
+        11..=15 => Key::F((n - 10) as u8),
+        17..=21 => Key::F((n - 11) as u8),
+        23 | 24 => Key::F((n - 12) as u8),
+        _ => return None,
+    })
+}
+
+/// Events waiting for the game.
+#[derive(Debug)]
+pub struct EventQueue {
+    events: [Option<KeyEvent>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    pub const fn new() -> Self {
+        EventQueue {
+            events: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds an event, returns false if the queue is full.
+    pub fn push(&mut self, event: KeyEvent) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+
+        self.events[(self.head + self.len) % QUEUE_SIZE] = Some(event);
+        self.len += 1;
+        true
+    }
+
+    /// Takes the oldest event.
+    pub fn pop(&mut self) -> Option<KeyEvent> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        event
+    }
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        EventQueue::new()
+    }
+}
+
+/// Decoder and queue for a terminal.
+#[derive(Debug)]
+pub struct Keyboard {
+    decoder: Decoder,
+    queue: EventQueue,
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Keyboard {
+            decoder: Decoder::new(),
+            queue: EventQueue::new(),
+        }
+    }
+
+    /// Decodes a byte and queues the press and release of the key it ends.
+    pub fn feed(&mut self, byte: u8) {
+        if let Some((key, modifiers)) = self.decoder.feed(byte) {
+            self.push(key, modifiers);
+        }
+    }
+
+    /// Ends a pending sequence, see `Decoder::flush`.
+    pub fn flush(&mut self) {
+        if let Some((key, modifiers)) = self.decoder.flush() {
+            self.push(key, modifiers);
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.decoder.is_pending()
+    }
+
+    pub fn next_event(&mut self) -> Option<KeyEvent> {
+        self.queue.pop()
+    }
+
+    fn push(&mut self, key: Key, modifiers: Modifiers) {
+        // only queue the press if the release fits too
+        if self.queue.len() + 2 <= QUEUE_SIZE {
+            self.queue.push(KeyEvent::press(key, modifiers));
+            self.queue.push(KeyEvent::release(key, modifiers));
+        }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Keyboard::new()
+    }
+}
+
+struct Console {
+    keyboard: Keyboard,
+    /// When the last byte came in.
+    last_byte: Option<Instant>,
+}
+
+static CONSOLE: Mutex<RefCell<Console>> = Mutex::new(RefCell::new(Console {
+    keyboard: Keyboard::new(),
+    last_byte: None,
+}));
+
+/// Decodes what was typed on the serial console since the last call. Input
+/// for the debug shell goes to it instead.
+pub fn poll() {
+    let now = Instant::now();
+
+    // `shell::readchar` may run a command, so it is not called in the
+    // critical section
+    while let Some(c) = shell::readchar() {
+        interrupt::free(|cs| {
+            let mut console = CONSOLE.borrow(cs).borrow_mut();
+            console.keyboard.feed(c);
+            console.last_byte = Some(now);
+        });
+    }
+
+    interrupt::free(|cs| {
+        let mut console = CONSOLE.borrow(cs).borrow_mut();
+        let expired = console
+            .last_byte
+            .map_or(true, |last| now - last >= ESCAPE_TIMEOUT);
+        if console.keyboard.is_pending() && expired {
+            console.keyboard.flush();
+        }
+    });
+}
+
+/// Takes the oldest key event from the serial console.
+pub fn next_event() -> Option<KeyEvent> {
+    interrupt::free(|cs| CONSOLE.borrow(cs).borrow_mut().keyboard.next_event())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<(Key, Modifiers)> {
+        let mut decoder = Decoder::new();
+        let mut keys: Vec<_> = bytes.iter().filter_map(|&b| decoder.feed(b)).collect();
+        keys.extend(decoder.flush());
+        keys
+    }
+
+    fn with(shift: bool, alt: bool, ctrl: bool) -> Modifiers {
+        Modifiers { shift, alt, ctrl }
+    }
+
+    #[test]
+    fn plain_keys() {
+        let none = Modifiers::NONE;
+        assert_eq!(
+            decode(b"aZ\r\x7f\t\x03"),
+            vec![
+                (Key::Char('a'), none),
+                (Key::Char('Z'), none),
+                (Key::Enter, none),
+                (Key::Backspace, none),
+                (Key::Tab, none),
+                (Key::Char('c'), with(false, false, true)),
+            ]
+        );
+        assert_eq!(
+            decode("ö€".as_bytes()),
+            vec![(Key::Char('ö'), none), (Key::Char('€'), none)]
+        );
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let none = Modifiers::NONE;
+        assert_eq!(
+            decode(b"\x1b[A\x1bOD\x1b[3~\x1b[15~\x1bOP\x1b[Z"),
+            vec![
+                (Key::Up, none),
+                (Key::Left, none),
+                (Key::Delete, none),
+                (Key::F(5), none),
+                (Key::F(1), none),
+                (Key::Tab, with(true, false, false)),
+            ]
+        );
+
+        // xterm modifiers, alt as an ESC prefix and a lone escape
+        assert_eq!(
+            decode(b"\x1b[1;5C\x1b[5;2~\x1bx\x1b\x1b[B\x1b"),
+            vec![
+                (Key::Right, with(false, false, true)),
+                (Key::PageUp, with(true, false, false)),
+                (Key::Char('x'), with(false, true, false)),
+                (Key::Down, with(false, true, false)),
+                (Key::Escape, none),
+            ]
+        );
+        assert_eq!(
+            decode(b"\x1b\x1b"),
+            vec![(Key::Escape, with(false, true, false))]
+        );
+
+        // unknown sequences are dropped, not typed
+        assert_eq!(decode(b"\x1b[200~q"), vec![(Key::Char('q'), none)]);
+    }
+
+    #[test]
+    fn queues_presses_and_releases() {
+        let mut keyboard = Keyboard::new();
+        for &b in b"\x1b[D" {
+            keyboard.feed(b);
+        }
+        keyboard.feed(ESC);
+        assert!(keyboard.is_pending());
+        keyboard.flush();
+
+        let none = Modifiers::NONE;
+        assert_eq!(
+            keyboard.next_event(),
+            Some(KeyEvent::press(Key::Left, none))
+        );
+        assert_eq!(
+            keyboard.next_event(),
+            Some(KeyEvent::release(Key::Left, none))
+        );
+        assert_eq!(
+            keyboard.next_event(),
+            Some(KeyEvent::press(Key::Escape, none))
+        );
+        assert_eq!(
+            keyboard.next_event(),
+            Some(KeyEvent::release(Key::Escape, none))
+        );
+        assert_eq!(keyboard.next_event(), None);
+
+        for _ in 0..QUEUE_SIZE {
+            keyboard.feed(b'x');
+        }
+        assert_eq!(keyboard.queue.len(), QUEUE_SIZE);
+    }
+}
@@ -13,12 +13,12 @@ extern crate register;
 #[macro_export]
 macro_rules! sprintln {
     () => {
-        $crate::serial::write("\n")
+        core::fmt::Write::write_str(&mut $crate::dmesg::ConsoleWriter, "\n").unwrap()
     };
     ($($arg:tt)*) => {{
-        let mut writer = $crate::serial::SerialWriter;
+        let mut writer = $crate::dmesg::ConsoleWriter;
         core::fmt::write(&mut writer, format_args!($($arg)*)).unwrap();
-        $crate::serial::write("\n");
+        core::fmt::Write::write_str(&mut writer, "\n").unwrap();
     }};
 }
 
@@ -46,13 +46,24 @@ macro_rules! entry {
     };
 }
 
+pub mod chainload;
 pub mod cpu;
+pub mod dmesg;
 pub mod error;
+pub mod game;
+pub mod gdb;
+pub mod gpio;
 pub mod gpu;
+pub mod input;
+pub mod keyboard;
+pub mod logger;
 pub mod memory;
 pub mod power;
+pub mod ring_buffer;
 pub mod serial;
+pub mod shell;
 pub mod timer;
+pub mod xmodem;
 
 #[cfg(target_arch = "aarch64")]
 pub mod prelude {
@@ -107,6 +118,8 @@ mod main {
         // Zeroes the .bss section
         r0::zero_bss(&mut __bss_start, &mut __bss_end);
 
+        super::dmesg::init();
+
         extern "Rust" {
             fn main() -> !;
         }
@@ -115,6 +128,7 @@ mod main {
             // May be hard to print but at least we tried.
             panic!("Failed to init serial: {}", e);
         }
+        super::logger::init();
 
         let exception_vectors_start: u64 = &_vectors as *const _ as u64;
         if exception_vectors_start.trailing_zeros() < 11 {
@@ -136,6 +150,10 @@ mod main {
         main();
     }
 
+    extern "C" {
+        fn _start();
+    }
+
     /// Prepare and execute transition from EL2 to EL1.
     #[inline]
     fn setup_and_enter_el1_from_el2() -> ! {
@@ -174,22 +192,27 @@ mod main {
         asm::eret()
     }
 
-    /// Entrypoint of the processor.
+    /// Called from `_start` in `boot.s` once there is a stack.
     ///
     /// Parks all cores except core0 and checks if we started in EL2. If
-    /// so, proceeds with setting up EL1.
-    #[link_section = ".text.boot"]
+    /// so, proceeds with setting up EL1. Kernels booted by `chainload` are
+    /// already in EL1.
     #[no_mangle]
-    pub unsafe extern "C" fn _start() -> ! {
+    pub unsafe extern "C" fn _start_rust() -> ! {
         const CORE_0: u64 = 0;
         const CORE_MASK: u64 = 0x3;
+        const EL1: u32 = CurrentEL::EL::EL1.value;
         const EL2: u32 = CurrentEL::EL::EL2.value;
 
-        if (CORE_0 == MPIDR_EL1.get() & CORE_MASK) && (EL2 == CurrentEL.get()) {
-            setup_and_enter_el1_from_el2()
+        if CORE_0 == MPIDR_EL1.get() & CORE_MASK {
+            match CurrentEL.get() {
+                EL2 => setup_and_enter_el1_from_el2(),
+                EL1 => reset(),
+                _ => {}
+            }
         }
 
-        // if not core0 or EL != 2, infinitely wait for events
+        // if not core0 or not in EL1 or EL2, infinitely wait for events
         loop {
             asm::wfe();
         }
@@ -198,6 +221,8 @@ mod main {
     #[panic_handler]
     pub fn panic(info: &PanicInfo) -> ! {
         sprintln!("{}", info);
+        super::cpu::backtrace::print();
+        super::dmesg::flush();
         loop {
             asm::wfe();
         }
@@ -214,5 +239,11 @@ mod main {
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("boot.s"));
+
 #[cfg(target_arch = "aarch64")]
 global_asm!(include_str!("exceptions.s"));
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("chainload.s"));
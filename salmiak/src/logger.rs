@@ -0,0 +1,291 @@
+//! Leveled logging through the `log` crate.
+//!
+//! `init` installs a logger that filters records per module, prefixes them
+//! with the time since boot and hands the line to every registered sink.
+//! Serial output is on from the start, `RING` and `FramebufferSink` can be
+//! added with `add_sink`.
+//!
+//! ```ignore
+//! logger::set_module_level("salmiak::gpu", LevelFilter::Warn);
+//! logger::add_sink(&logger::RING);
+//! info!("{} snakes", 3);
+//! ```
+use crate::cpu::interrupt::{self, Mutex};
+use crate::dmesg::ConsoleWriter;
+use crate::timer::{Duration, Instant};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use log::{Log, Metadata, Record};
+
+mod framebuffer;
+mod ring;
+
+pub use self::framebuffer::FramebufferSink;
+pub use self::ring::{RingSink, LOG_RING_SIZE, RING};
+pub use log::{debug, error, info, trace, warn, Level, LevelFilter};
+
+/// Longest line handed to the sinks, longer ones are cut.
+pub const LINE_LENGTH: usize = 256;
+
+/// Number of sinks that can be added.
+pub const MAX_SINKS: usize = 4;
+
+/// Number of modules that can have their own level.
+pub const MAX_FILTERS: usize = 16;
+
+/// Boot chatter is logged at info, which release builds leave out.
+#[cfg(debug_assertions)]
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Debug;
+#[cfg(not(debug_assertions))]
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+/// Receives every formatted line that passes the filters.
+pub trait Sink: Sync {
+    /// `line` has no line ending. Called with IRQs masked.
+    fn write(&self, level: Level, line: &str);
+}
+
+/// Writes to the serial console, which also keeps the line in `dmesg`.
+pub struct UartSink;
+
+impl Sink for UartSink {
+    fn write(&self, _level: Level, line: &str) {
+        let _ = ConsoleWriter.write_str(line);
+        let _ = ConsoleWriter.write_str("\n");
+    }
+}
+
+/// Per module levels, the longest matching module path wins.
+pub struct Filters {
+    default: LevelFilter,
+    modules: [Option<(&'static str, LevelFilter)>; MAX_FILTERS],
+}
+
+impl Filters {
+    pub const fn new(default: LevelFilter) -> Self {
+        Filters {
+            default,
+            modules: [None; MAX_FILTERS],
+        }
+    }
+
+    pub fn set_default(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+
+    /// Sets the level of `module` and its children. Returns false if there
+    /// already are `MAX_FILTERS` modules.
+    pub fn set(&mut self, module: &'static str, level: LevelFilter) -> bool {
+        let slot = self
+            .modules
+            .iter_mut()
+            .find(|m| m.map_or(true, |(name, _)| name == module));
+
+        match slot {
+            Some(slot) => {
+                *slot = Some((module, level));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Level of `target`, a module path like `salmiak::gpu::mailbox`.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter_map(|m| *m)
+            .filter(|(module, _)| {
+                target.starts_with(module)
+                    && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| level)
+    }
+
+    /// Most verbose level of any module.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter_map(|m| *m)
+            .map(|(_, level)| level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+/// Line buffer that cuts what does not fit.
+struct Line {
+    buf: [u8; LINE_LENGTH],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            buf: [0; LINE_LENGTH],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // only whole characters are added
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(LINE_LENGTH - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+fn format_record<W: Write>(w: &mut W, since_boot: Duration, record: &Record) -> fmt::Result {
+    write!(
+        w,
+        "[{:>5}.{:06}] {:<5} {}: {}",
+        since_boot.as_secs(),
+        since_boot.subsec_micros(),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+static UART: UartSink = UartSink;
+static FILTERS: Mutex<RefCell<Filters>> = Mutex::new(RefCell::new(Filters::new(DEFAULT_LEVEL)));
+static SINKS: Mutex<RefCell<[Option<&'static dyn Sink>; MAX_SINKS]>> =
+    Mutex::new(RefCell::new([None; MAX_SINKS]));
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level()
+            <= interrupt::free(|cs| FILTERS.borrow(cs).borrow().level_for(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = Line::new();
+        let _ = format_record(&mut line, Instant::now().since_boot(), record);
+
+        interrupt::free(|cs| {
+            for sink in SINKS.borrow(cs).borrow().iter().filter_map(|s| *s) {
+                sink.write(record.level(), line.as_str());
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn update_max_level() {
+    log::set_max_level(interrupt::free(|cs| {
+        FILTERS.borrow(cs).borrow().max_level()
+    }));
+}
+
+/// Installs the logger with the serial console as the only sink.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        add_sink(&UART);
+        update_max_level();
+    }
+}
+
+/// Sends the log to `sink` as well. Returns false if there already are
+/// `MAX_SINKS`.
+pub fn add_sink(sink: &'static dyn Sink) -> bool {
+    interrupt::free(|cs| {
+        let mut sinks = SINKS.borrow(cs).borrow_mut();
+        match sinks.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => {
+                *slot = Some(sink);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Sets the level of modules without their own.
+pub fn set_level(level: LevelFilter) {
+    interrupt::free(|cs| FILTERS.borrow(cs).borrow_mut().set_default(level));
+    update_max_level();
+}
+
+/// Sets the level of `module`, e.g. `salmiak::memory`, and its children.
+/// Returns false if there already are `MAX_FILTERS` modules.
+pub fn set_module_level(module: &'static str, level: LevelFilter) -> bool {
+    let set = interrupt::free(|cs| FILTERS.borrow(cs).borrow_mut().set(module, level));
+    update_max_level();
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_filters() {
+        let mut filters = Filters::new(LevelFilter::Info);
+        assert!(filters.set("salmiak::gpu", LevelFilter::Warn));
+        assert!(filters.set("salmiak::gpu::mailbox", LevelFilter::Trace));
+
+        assert_eq!(filters.level_for("sneka"), LevelFilter::Info);
+        assert_eq!(filters.level_for("salmiak::gpu"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("salmiak::gpu::font"), LevelFilter::Warn);
+        assert_eq!(
+            filters.level_for("salmiak::gpu::mailbox"),
+            LevelFilter::Trace
+        );
+        // not a child of salmiak::gpu
+        assert_eq!(filters.level_for("salmiak::gpux"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+
+        filters.set("salmiak::gpu::mailbox", LevelFilter::Off);
+        assert_eq!(filters.max_level(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn formatting() {
+        let mut line = Line::new();
+        format_record(
+            &mut line,
+            Duration::from_micros(1_250_000),
+            &Record::builder()
+                .level(Level::Warn)
+                .target("salmiak::memory")
+                .args(format_args!("{} MB left", 3))
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            line.as_str(),
+            "[    1.250000] WARN  salmiak::memory: 3 MB left"
+        );
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let mut line = Line::new();
+        for _ in 0..LINE_LENGTH - 1 {
+            line.write_char('x').unwrap();
+        }
+        line.write_str("åäö").unwrap();
+
+        assert_eq!(line.as_str().len(), LINE_LENGTH - 1);
+    }
+}
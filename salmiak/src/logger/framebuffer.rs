@@ -0,0 +1,57 @@
+//! Sink drawing log lines on the screen.
+use super::{Level, Sink};
+use crate::cpu::interrupt::{self, Mutex};
+use crate::gpu::{self, font, Color};
+use core::cell::Cell;
+
+/// Draws every line below the previous one and scrolls the screen when it
+/// reaches the bottom. Does nothing until `gpu::init` has set up the frame
+/// buffer. Anything drawn into the back buffer covers it on the next
+/// `Gpu::swap`.
+pub struct FramebufferSink {
+    /// Top of the next line.
+    y: Mutex<Cell<u32>>,
+}
+
+impl FramebufferSink {
+    pub const fn new() -> Self {
+        FramebufferSink {
+            y: Mutex::new(Cell::new(0)),
+        }
+    }
+}
+
+impl Default for FramebufferSink {
+    fn default() -> Self {
+        FramebufferSink::new()
+    }
+}
+
+fn color(level: Level) -> Color {
+    match level {
+        Level::Error => Color::RED,
+        Level::Warn => Color::YELLOW,
+        Level::Info => Color::WHITE,
+        Level::Debug | Level::Trace => Color::GRAY,
+    }
+}
+
+impl Sink for FramebufferSink {
+    fn write(&self, level: Level, line: &str) {
+        let screen = match gpu::screen() {
+            Some(screen) if screen.height >= font::GLYPH_HEIGHT => screen,
+            _ => return,
+        };
+
+        interrupt::free(|cs| {
+            let y = self.y.borrow(cs);
+            if y.get() + font::GLYPH_HEIGHT > screen.height {
+                screen.scroll_up(font::GLYPH_HEIGHT, &Color::BLACK);
+                y.set(screen.height - font::GLYPH_HEIGHT);
+            }
+
+            screen.draw_text(0, y.get(), line, &color(level), &Color::BLACK);
+            y.set(y.get() + font::GLYPH_HEIGHT);
+        });
+    }
+}
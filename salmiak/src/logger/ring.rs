@@ -0,0 +1,39 @@
+//! Sink keeping the latest log lines in memory.
+use super::{Level, Sink};
+use crate::cpu::interrupt::{self, Mutex};
+use crate::ring_buffer::{TextRing, TEXT_RING_SIZE};
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+/// Bytes of log text kept by `RING`.
+pub const LOG_RING_SIZE: usize = TEXT_RING_SIZE;
+
+pub struct RingSink {
+    ring: Mutex<RefCell<TextRing>>,
+}
+
+/// The ring sink, add it with `logger::add_sink(&logger::RING)`.
+pub static RING: RingSink = RingSink::new();
+
+impl RingSink {
+    const fn new() -> Self {
+        RingSink {
+            ring: Mutex::new(RefCell::new(TextRing::new())),
+        }
+    }
+
+    /// Writes the kept lines to `w`, oldest first.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> fmt::Result {
+        interrupt::free(|cs| self.ring.borrow(cs).borrow().write_to(w))
+    }
+}
+
+impl Sink for RingSink {
+    fn write(&self, _level: Level, line: &str) {
+        interrupt::free(|cs| {
+            let mut ring = self.ring.borrow(cs).borrow_mut();
+            ring.push(line.as_bytes());
+            ring.push(b"\n");
+        });
+    }
+}
@@ -6,14 +6,19 @@ pub use core::{
     ptr::{read_volatile, write_volatile},
 };
 
-pub use self::alloc::Allocator;
+pub use self::alloc::{Allocator, HeapStats};
 use crate::gpu::mailbox::{ARMMemory, MailboxPropertyBufferBuilder};
+use crate::logger::{debug, info};
 use crate::memory::alloc::align_up;
 
 use cortex_a::{barrier, regs::*};
 
 pub const MB: usize = 0x100_000;
 
+/// End of the ARM local peripherals at 0x4000_0000. RAM, the GPU memory and
+/// all peripherals are below it.
+const LOCAL_PERIPHERALS_END: usize = 0x4004_0000;
+
 register_bitfields! {u64,
     // AArch64 Reference Manual page 2150
     STAGE1_DESCRIPTOR [
@@ -107,7 +112,7 @@ impl DerefMut for PageTable {
 }
 
 pub fn init(kernel_end: *const u8) -> Result<(), SalmiakError> {
-    sprintln!("initializing memory...");
+    info!("initializing memory...");
     let mut arm_memory: ARMMemory = Default::default();
     let res = MailboxPropertyBufferBuilder::new()
         .get_arm_memory(&mut arm_memory)
@@ -123,14 +128,14 @@ pub fn init(kernel_end: *const u8) -> Result<(), SalmiakError> {
 
     let heap_start = align_up(kernel_end as usize, /*1*/ MB);
 
-    sprintln!("* setting up allocators");
-    sprintln!("    Kernel End: {:p}", kernel_end);
-    sprintln!("    Heap Start: {:p}", heap_start as *const ());
-    sprintln!(
+    debug!("setting up allocators");
+    debug!("    Kernel End: {:p}", kernel_end);
+    debug!("    Heap Start: {:p}", heap_start as *const ());
+    debug!(
         "    Heap End: {:p}",
         (arm_memory.base_address + arm_memory.size) as *const ()
     );
-    sprintln!("    Heap Size: {} Mb", arm_memory.size / MB);
+    debug!("    Heap Size: {} Mb", arm_memory.size / MB);
 
     #[cfg(target_arch = "aarch64")]
     unsafe {
@@ -139,15 +144,15 @@ pub fn init(kernel_end: *const u8) -> Result<(), SalmiakError> {
             arm_memory.base_address + arm_memory.size - heap_start,
         );
 
-        sprintln!("* allocators initialized");
+        debug!("allocators initialized");
 
         // set up paging
-        sprintln!("* setting up paging");
+        debug!("setting up paging");
         init_mmu();
-        sprintln!("* paging enabled");
+        debug!("paging enabled");
     }
 
-    sprintln!("done!");
+    info!("memory initialized");
     Ok(())
 }
 
@@ -270,3 +275,98 @@ unsafe fn init_mmu() {
     // Force MMU init to complete before next instruction
     barrier::isb(barrier::SY);
 }
+
+/// Usage of the kernel heap. Child allocators count as used in full.
+#[cfg(target_arch = "aarch64")]
+pub fn heap_stats() -> HeapStats {
+    unsafe { crate::ALLOCATOR.stats() }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn heap_stats() -> HeapStats {
+    HeapStats::default()
+}
+
+/// Returns true if the `len` bytes at `addr` are RAM or peripherals, so that
+/// accessing them does not fault. The rest of the identity mapping is
+/// either not mapped or not backed by anything.
+pub fn is_mapped(addr: usize, len: usize) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => end <= LOCAL_PERIPHERALS_END,
+        None => false,
+    }
+}
+
+/// Returns true if `addr` is part of the read-only kernel image (text and rodata).
+#[cfg(target_arch = "aarch64")]
+pub fn is_read_only(addr: usize) -> bool {
+    extern "C" {
+        static __ro_start: u64;
+        static __ro_end: u64;
+    }
+
+    unsafe { addr >= &__ro_start as *const _ as usize && addr < &__ro_end as *const _ as usize }
+}
+
+/// Writes the instruction `insn` to `addr` and makes it visible to instruction
+/// fetches. Pages in the read-only part of the kernel are made writable for the
+/// duration of the write.
+///
+/// # Safety
+///
+/// Overwrites code that might be executing.
+#[cfg(target_arch = "aarch64")]
+pub unsafe fn write_instruction(addr: usize, insn: u32) {
+    const PAGESIZE: usize = 4096;
+    let page = addr / PAGESIZE;
+    let read_only = is_read_only(addr);
+    assert!(
+        !read_only || page < NUM_ENTRIES_4KIB,
+        "Read-only address outside of the level 3 table."
+    );
+
+    let original = if read_only {
+        SINGLE_LVL3_TABLE[page]
+    } else {
+        0
+    };
+    if read_only {
+        set_lvl3_entry(
+            page,
+            (original & !STAGE1_DESCRIPTOR::AP::RO_EL1.mask) | STAGE1_DESCRIPTOR::AP::RW_EL1.value,
+        );
+    }
+
+    (addr as *mut u32).write_volatile(insn);
+
+    // Clean the data cache and invalidate the instruction cache to the point
+    // of unification so that the new instruction is fetched.
+    asm!("dc cvau, $0
+          dsb ish
+          ic ivau, $0
+          dsb ish
+          isb"
+         :
+         : "r"(addr)
+         : "memory"
+         : "volatile");
+
+    if read_only {
+        set_lvl3_entry(page, original);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn set_lvl3_entry(page: usize, entry: u64) {
+    SINGLE_LVL3_TABLE[page] = entry;
+
+    // make the descriptor visible to the table walker and drop stale entries
+    asm!("dsb ishst
+          tlbi vmalle1
+          dsb ish
+          isb"
+         :
+         :
+         : "memory"
+         : "volatile");
+}
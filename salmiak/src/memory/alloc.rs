@@ -40,6 +40,18 @@ impl OriginAllocator {
     pub fn initialize(&mut self, start: usize, size: usize) {
         self.inner = BumpAllocator::new(start, size);
     }
+
+    pub fn stats(&self) -> HeapStats {
+        self.inner.stats()
+    }
+}
+
+/// Usage of an allocator's memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HeapStats {
+    pub start: usize,
+    pub size: usize,
+    pub used: usize,
 }
 
 pub trait Allocator {
@@ -84,6 +96,14 @@ impl BumpAllocator {
             next: AtomicUsize::new(0),
         }
     }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            start: self.heap_start,
+            size: self.heap_end - self.heap_start,
+            used: self.next.load(Ordering::Relaxed) - self.heap_start,
+        }
+    }
 }
 
 impl Allocator for BumpAllocator {
@@ -7,6 +7,9 @@ const W_CLR: u32 = 0xffff_ffcf;
 const W_FULL_RESET: u32 = 0x0000_0020;
 
 pub fn reset() -> ! {
+    // keep the log for the next boot
+    crate::dmesg::flush();
+
     unsafe {
         // use a timeout of 10 ticks (~150us)
         W_DOG.write_volatile(W_PASSWORD | 10);
@@ -0,0 +1,273 @@
+//! Lock-free single producer, single consumer byte queue.
+//!
+//! Meant for passing data between an interrupt handler and the rest of the
+//! kernel: one side only pushes and the other only pops, so the two indices
+//! are each written by one side only and no locking is needed.
+//!
+//! `TextRing` is the lossy counterpart for logs, it overwrites the oldest
+//! text instead of refusing new bytes.
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Capacity of a `ByteRingBuffer`, a power of two.
+pub const RING_BUFFER_SIZE: usize = 1024;
+
+pub struct ByteRingBuffer {
+    buffer: UnsafeCell<[u8; RING_BUFFER_SIZE]>,
+    /// Total number of bytes pushed, only written by the producer.
+    head: AtomicUsize,
+    /// Total number of bytes popped, only written by the consumer.
+    tail: AtomicUsize,
+}
+
+// The producer only writes slots the consumer is done with and the other way
+// around, see `push` and `pop`.
+unsafe impl Sync for ByteRingBuffer {}
+
+impl ByteRingBuffer {
+    pub const fn new() -> Self {
+        ByteRingBuffer {
+            buffer: UnsafeCell::new([0; RING_BUFFER_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.head
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == RING_BUFFER_SIZE
+    }
+
+    /// Adds a byte to the queue, returns false if it is full.
+    ///
+    /// # Safety
+    ///
+    /// There can only be one producer at a time, callers pushing from more
+    /// than one context have to serialize them, e.g. with a critical section.
+    pub unsafe fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == RING_BUFFER_SIZE {
+            return false;
+        }
+
+        (*self.buffer.get())[head % RING_BUFFER_SIZE] = byte;
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Takes the oldest byte from the queue.
+    ///
+    /// # Safety
+    ///
+    /// There can only be one consumer at a time, see `push`.
+    pub unsafe fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let byte = (*self.buffer.get())[tail % RING_BUFFER_SIZE];
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
+
+impl Default for ByteRingBuffer {
+    fn default() -> Self {
+        ByteRingBuffer::new()
+    }
+}
+
+/// Capacity of a `TextRing`.
+pub const TEXT_RING_SIZE: usize = 8 * 1024;
+
+/// Log text that overwrites the oldest lines when full. Laid out as C so
+/// that it can be read back from memory written by an earlier boot.
+#[repr(C)]
+pub struct TextRing {
+    /// Total number of bytes ever written.
+    written: usize,
+    buf: [u8; TEXT_RING_SIZE],
+}
+
+impl TextRing {
+    pub const fn new() -> Self {
+        TextRing {
+            written: 0,
+            buf: [0; TEXT_RING_SIZE],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.written = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.written == 0
+    }
+
+    pub fn copy_from(&mut self, other: &TextRing) {
+        self.written = other.written;
+        self.buf.copy_from_slice(&other.buf);
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[self.written % TEXT_RING_SIZE] = b;
+            self.written = self.written.wrapping_add(1);
+        }
+    }
+
+    /// The two halves of the kept text, oldest first.
+    fn contents(&self) -> (&[u8], &[u8]) {
+        if self.written <= TEXT_RING_SIZE {
+            (&self.buf[..self.written], &[])
+        } else {
+            let start = self.written % TEXT_RING_SIZE;
+            (&self.buf[start..], &self.buf[..start])
+        }
+    }
+
+    /// Writes whole lines to `w`, the oldest one is skipped if it has been
+    /// partly overwritten. Bytes that are not UTF-8 are written as `?`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> fmt::Result {
+        let (first, second) = self.contents();
+        let mut skip = self.written > TEXT_RING_SIZE;
+
+        for half in [first, second].iter() {
+            let mut half: &[u8] = half;
+            if skip {
+                match half.iter().position(|&b| b == b'\n') {
+                    Some(i) => {
+                        half = &half[i + 1..];
+                        skip = false;
+                    }
+                    None => continue,
+                }
+            }
+
+            write_lossy(w, half)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for TextRing {
+    fn default() -> Self {
+        TextRing::new()
+    }
+}
+
+fn write_lossy<W: Write>(w: &mut W, mut bytes: &[u8]) -> fmt::Result {
+    loop {
+        match core::str::from_utf8(bytes) {
+            Ok(s) => return w.write_str(s),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                w.write_str(unsafe { core::str::from_utf8_unchecked(valid) })?;
+                w.write_char('?')?;
+                bytes = &rest[e.error_len().unwrap_or_else(|| rest.len())..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn fifo_order_and_full() {
+        let ring = ByteRingBuffer::new();
+
+        unsafe {
+            assert_eq!(ring.pop(), None);
+            for i in 0..RING_BUFFER_SIZE {
+                assert!(ring.push(i as u8));
+            }
+            assert!(ring.is_full());
+            assert!(!ring.push(0xff));
+
+            assert_eq!(ring.pop(), Some(0));
+            assert!(ring.push(0xff));
+            for i in 1..RING_BUFFER_SIZE {
+                assert_eq!(ring.pop(), Some(i as u8));
+            }
+            assert_eq!(ring.pop(), Some(0xff));
+            assert!(ring.is_empty());
+        }
+    }
+
+    #[test]
+    fn producer_and_consumer_threads() {
+        const COUNT: usize = 100_000;
+        let ring = Arc::new(ByteRingBuffer::new());
+
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                for i in 0..COUNT {
+                    while !unsafe { ring.push(i as u8) } {
+                        thread::yield_now();
+                    }
+                }
+            })
+        };
+
+        for i in 0..COUNT {
+            loop {
+                if let Some(byte) = unsafe { ring.pop() } {
+                    assert_eq!(byte, i as u8);
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+
+        producer.join().unwrap();
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn text_ring_keeps_whole_lines() {
+        let mut ring = TextRing::new();
+        ring.push(b"first\n");
+
+        let mut text = String::new();
+        ring.write_to(&mut text).unwrap();
+        assert_eq!(text, "first\n");
+
+        // push out the start of "first"
+        let filler = [b'x'; TEXT_RING_SIZE - 10];
+        ring.push(&filler);
+        ring.push(b"\nlast\n");
+
+        let mut text = String::new();
+        ring.write_to(&mut text).unwrap();
+        assert!(text.ends_with("x\nlast\n"));
+        assert!(!text.contains("rst"));
+        assert_eq!(text.len(), TEXT_RING_SIZE - 10 + "\nlast\n".len());
+    }
+
+    #[test]
+    fn text_ring_replaces_garbage() {
+        let mut ring = TextRing::new();
+        ring.push(b"ok \xff\xfe done\n");
+
+        let mut text = String::new();
+        ring.write_to(&mut text).unwrap();
+        assert_eq!(text, "ok ?? done\n");
+    }
+}
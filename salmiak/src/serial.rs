@@ -1,69 +1,163 @@
-use crate::gpu::mailbox::{self, MailboxPropertyBufferBuilder};
-
+//! Serial console.
+//!
+//! `sprintln!` and the functions here go to the console port, the PL011 by
+//! default or the mini UART with the `mini-uart` feature. Both implement
+//! `SerialPort` and can also be used directly.
+use crate::gpio::{self, Function, Pull};
 use crate::prelude::*;
+use crate::timer::{Duration, Instant};
 use core::fmt::{Error, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-const UART_DR: u32 = 0x3F20_1000;
+pub mod mini_uart;
+pub mod pl011;
 
-// The GPIO registers base address.
-const GPIO_BASE: u32 = 0x3F20_0000;
+pub use self::mini_uart::MiniUart;
+pub use self::pl011::Pl011;
 
-// Controls actuation of pull up/down to ALL GPIO pins.
-const GPPUD: *mut u32 = (GPIO_BASE + 0x94) as *mut u32;
+/// Transmit and receive pins of both UARTs.
+const TXD: u8 = 14;
+const RXD: u8 = 15;
 
-// Controls actuation of pull up/down for specific GPIO pin.
-const GPPUDCLK0: *mut u32 = (GPIO_BASE + 0x98) as *mut u32;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
 
-const GPFSEL1: *mut u32 = 0x3f20_0004 as *mut u32;
-// const GPSET0: u32 = 0x3f20001C;
-// const GPCLR0: u32 = 0x3f200028;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
 
-const UART0_DR: *mut u32 = UART_DR as *mut u32;
-// const UART0_RSRECR: u32 = (UART_DR + 0x04);
-const UART0_FR: *mut u32 = (UART_DR + 0x18) as *mut u32;
-// const UART0_ILPR: u32 = (UART_DR + 0x20);
-const UART0_IBRD: *mut u32 = (UART_DR + 0x24) as *mut u32;
-const UART0_FBRD: *mut u32 = (UART_DR + 0x28) as *mut u32;
-const UART0_LCRH: *mut u32 = (UART_DR + 0x2C) as *mut u32;
-const UART0_CR: *mut u32 = (UART_DR + 0x30) as *mut u32;
-// const UART0_IFLS: u32 = (UART_DR + 0x34);
-// const UART0_IMSC: u32 = (UART_DR + 0x38);
-// const UART0_RIS: u32 = (UART_DR + 0x3C);
-// const UART0_MIS: u32 = (UART_DR + 0x40);
-const UART0_ICR: *mut u32 = (UART_DR + 0x44) as *mut u32;
-// const UART0_DMACR: u32 = (UART_DR + 0x48);
-// const UART0_ITCR: u32 = (UART_DR + 0x80);
-// const UART0_ITIP: u32 = (UART_DR + 0x84);
-// const UART0_ITOP: u32 = (UART_DR + 0x88);
-// const UART0_TDR: u32 = (UART_DR + 0x8C);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopBits {
+    One,
+    Two,
+}
 
-fn transmit_fifo_full() -> bool {
-    unsafe { UART0_FR.read_volatile() & (1 << 5) != 0 }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
 }
 
-pub fn readchar() -> Option<u8> {
-    unsafe {
-        if UART0_FR.read_volatile() & (1 << 4) == 0 {
-            let c = UART0_DR.read_volatile() as u8;
-            if c != 0 {
-                return Some(c);
-            }
+impl Default for SerialConfig {
+    /// 115200 baud, 8N1.
+    fn default() -> Self {
+        SerialConfig {
+            baud: 115_200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
         }
     }
+}
 
-    None
+pub trait SerialPort: Sync {
+    /// Sets up the port and routes GPIO14/15 to it.
+    fn init(&self, config: &SerialConfig) -> Result<(), SalmiakError>;
+
+    /// Takes a received byte without blocking.
+    fn readchar(&self) -> Option<u8>;
+
+    /// Queues a byte for sending without blocking, returns false if there is
+    /// no room for it.
+    fn try_writechar(&self, c: u8) -> bool;
+
+    /// Sends a byte, blocking until there is room for it.
+    fn writechar(&self, c: u8) {
+        while !self.try_writechar(c) {}
+    }
+
+    /// Switches to interrupt driven I/O, ports without interrupts keep polling.
+    fn enable_interrupts(&self) {}
+
+    /// Blocks until everything queued has been sent.
+    fn flush(&self);
 }
 
-pub fn writechar(c: u8) {
-    while transmit_fifo_full() {}
-    unsafe {
-        UART0_DR.write_volatile(u32::from(c));
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Console {
+    Pl011,
+    MiniUart,
+}
+
+impl Console {
+    pub fn port(self) -> &'static dyn SerialPort {
+        match self {
+            Console::Pl011 => &Pl011,
+            Console::MiniUart => &MiniUart,
+        }
+    }
+}
+
+#[cfg(not(feature = "mini-uart"))]
+const DEFAULT_CONSOLE: Console = Console::Pl011;
+#[cfg(feature = "mini-uart")]
+const DEFAULT_CONSOLE: Console = Console::MiniUart;
+
+static CONSOLE: AtomicUsize = AtomicUsize::new(DEFAULT_CONSOLE as usize);
+
+/// The port used by `sprintln!`.
+pub fn console() -> Console {
+    match CONSOLE.load(Ordering::Relaxed) {
+        c if c == Console::MiniUart as usize => Console::MiniUart,
+        _ => Console::Pl011,
     }
 }
 
+/// Sets up `console` and makes it the console. Only one of the ports can
+/// have GPIO14/15 at a time.
+pub fn set_console(console: Console, config: &SerialConfig) -> Result<(), SalmiakError> {
+    console.port().init(config)?;
+    CONSOLE.store(console as usize, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Selects `function` for GPIO14/15 and disables their pull up/down.
+fn setup_pins(function: Function) {
+    gpio::set_function(TXD, function);
+    gpio::set_function(RXD, function);
+    gpio::set_pulls(&[TXD, RXD], Pull::None);
+}
+
+/// Takes a received byte from the console without blocking.
+pub fn readchar() -> Option<u8> {
+    console().port().readchar()
+}
+
+/// Queues a byte for sending on the console without blocking, returns false
+/// if there is no room for it.
+pub fn try_writechar(c: u8) -> bool {
+    console().port().try_writechar(c)
+}
+
+/// Sends a byte on the console.
+pub fn writechar(c: u8) {
+    console().port().writechar(c)
+}
+
+/// Switches the console to interrupt driven I/O if it supports it.
+pub fn enable_interrupts() {
+    console().port().enable_interrupts()
+}
+
+/// Blocks until everything queued on the console has been sent.
+pub fn flush() {
+    console().port().flush()
+}
+
+/// Sends `msg` on the console as UTF-8.
 pub fn write(msg: &str) {
-    for c in msg.chars() {
-        writechar(c as u8)
+    for &c in msg.as_bytes() {
+        writechar(c)
     }
 }
 
@@ -76,71 +170,41 @@ impl Write for SerialWriter {
     }
 }
 
-pub fn delay(count: u32) {
-    for _ in 0..count {
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            asm!("nop" :::: "volatile");
-        }
-    }
+/// Byte stream with timeouts for protocols like `xmodem` and `chainload`.
+pub trait Transport {
+    /// Waits at most `timeout` for a byte.
+    fn read(&mut self, timeout: Duration) -> Option<u8>;
+    fn write(&mut self, bytes: &[u8]);
 }
 
-pub fn init() -> Result<(), SalmiakError> {
-    // Disable UART0
-    unsafe {
-        UART0_CR.write_volatile(0x0);
-    }
+/// The console as a `Transport`.
+pub struct ConsoleTransport;
 
-    // we want consistent divisor values and
-    // therefore set the clock rate of the UART
-    let res = MailboxPropertyBufferBuilder::new()
-        .set_clock_rate(
-            mailbox::clock::UART,
-            4_000_000, // 4 MHz
-            0,         // skip turbo
-            None,
-        )
-        .submit();
-
-    if !res {
-        return Err(
-            SalmiakErrorKind::InitSerialError("Failed to set serial clockrate".to_owned()).into(),
-        );
+impl Transport for ConsoleTransport {
+    fn read(&mut self, timeout: Duration) -> Option<u8> {
+        let start = Instant::now();
+        loop {
+            if let Some(c) = readchar() {
+                return Some(c);
+            }
+            if start.elapsed() >= timeout {
+                return None;
+            }
+        }
     }
 
-    unsafe {
-        let mut ra = GPFSEL1.read_volatile();
-        ra &= !((7 << 12) | (7 << 15)); //gpio14, gpio15
-        ra |= (4 << 12) | (4 << 15); //alt0
-        GPFSEL1.write_volatile(ra);
-
-        // Disable pull up/down for all GPIO pins and delay for 150 cycles
-        GPPUD.write_volatile(0x0);
-        delay(150);
-
-        // Disable pull up/down for pin 14,15 & delay for 150 cycles.
-        GPPUDCLK0.write_volatile((1 << 14) | (1 << 15));
-        delay(150);
-
-        // Write 0 to GPPUDCLK0 to make it take effect.
-        GPPUDCLK0.write_volatile(0x0);
-
-        // Clear pending interrupts.
-        UART0_ICR.write_volatile(0x7ff);
-
-        // Set integer & fractional part of baud rate.
-        // Divider = UART_CLOCK/(16 * Baud)
-        // Fraction part register = (Fractional part * 64) + 0.5
-        // UART_CLOCK = 3000000; Baud = 115200.
-
-        UART0_IBRD.write_volatile(2);
-        UART0_FBRD.write_volatile(0xb);
+    fn write(&mut self, bytes: &[u8]) {
+        for &c in bytes {
+            writechar(c);
+        }
+    }
+}
 
-        // Enable FIFO & 8 bit data transmissio (1 stop bit, no parity).
-        UART0_LCRH.write_volatile(0b11 << 5);
+/// Sets up the console for 115200 baud, 8N1.
+pub fn init() -> Result<(), SalmiakError> {
+    init_with(SerialConfig::default())
+}
 
-        // Enable UART0, receive & transfer part of UART.
-        UART0_CR.write_volatile((1) | (1 << 8) | (1 << 9));
-        Ok(())
-    }
+pub fn init_with(config: SerialConfig) -> Result<(), SalmiakError> {
+    console().port().init(&config)
 }
@@ -0,0 +1,176 @@
+//! Driver for the mini UART of the auxiliary peripherals.
+//!
+//! On boards where the PL011 is wired to Bluetooth the mini UART is the one
+//! on GPIO14/15. Its baud rate is derived from the core clock, which scales
+//! with the CPU frequency unless `core_freq` is fixed (`enable_uart=1` in
+//! `config.txt` does that). It only supports 7 or 8 data bits without
+//! parity and one stop bit and is always polled
+//! (BCM2835 ARM Peripherals, section 2.2).
+use super::{DataBits, Parity, SerialConfig, SerialPort, StopBits};
+use crate::gpio::Function;
+use crate::gpu::mailbox::{self, ClockRate, MailboxPropertyBufferBuilder};
+use crate::prelude::*;
+
+const AUX_BASE: u32 = mem_constants::MMIO_BASE + 0x0021_5000;
+const AUX_ENABLES: *mut u32 = (AUX_BASE + 0x04) as *mut u32;
+const AUX_MU_IO: *mut u32 = (AUX_BASE + 0x40) as *mut u32;
+const AUX_MU_IER: *mut u32 = (AUX_BASE + 0x44) as *mut u32;
+const AUX_MU_IIR: *mut u32 = (AUX_BASE + 0x48) as *mut u32;
+const AUX_MU_LCR: *mut u32 = (AUX_BASE + 0x4C) as *mut u32;
+const AUX_MU_MCR: *mut u32 = (AUX_BASE + 0x50) as *mut u32;
+const AUX_MU_LSR: *mut u32 = (AUX_BASE + 0x54) as *mut u32;
+const AUX_MU_CNTL: *mut u32 = (AUX_BASE + 0x60) as *mut u32;
+const AUX_MU_BAUD: *mut u32 = (AUX_BASE + 0x68) as *mut u32;
+
+const AUX_ENABLE_MINI_UART: u32 = 1;
+
+const LSR_DATA_READY: u32 = 1;
+const LSR_TX_EMPTY: u32 = 1 << 5;
+const LSR_TX_IDLE: u32 = 1 << 6;
+
+/// Clears both FIFOs.
+const IIR_CLEAR_FIFOS: u32 = 0xc6;
+
+/// Enables the receiver and the transmitter.
+const CNTL_RX_TX: u32 = 0b11;
+
+pub struct MiniUart;
+
+/// Value of the baud rate register for `baud` with a core clock of `clock`
+/// Hz. The baud rate is `clock / (8 * (register + 1))`.
+fn baud_register(clock: u32, baud: u32) -> Option<u32> {
+    if baud == 0 {
+        return None;
+    }
+
+    let eight_baud = u64::from(baud) * 8;
+    let divisor = (u64::from(clock) + eight_baud / 2) / eight_baud;
+
+    match divisor {
+        0 => None,
+        d if d > 0x1_0000 => None,
+        d => Some(d as u32 - 1),
+    }
+}
+
+/// Value of the line control register, or `None` for settings the mini UART
+/// does not have.
+fn line_control(config: &SerialConfig) -> Option<u32> {
+    if config.parity != Parity::None || config.stop_bits != StopBits::One {
+        return None;
+    }
+
+    match config.data_bits {
+        DataBits::Seven => Some(0),
+        // the data sheet says 0b01 but both bits have to be set
+        DataBits::Eight => Some(0b11),
+        _ => None,
+    }
+}
+
+fn receive_fifo_empty() -> bool {
+    unsafe { AUX_MU_LSR.read_volatile() & LSR_DATA_READY == 0 }
+}
+
+fn transmit_fifo_full() -> bool {
+    unsafe { AUX_MU_LSR.read_volatile() & LSR_TX_EMPTY == 0 }
+}
+
+impl SerialPort for MiniUart {
+    fn init(&self, config: &SerialConfig) -> Result<(), SalmiakError> {
+        let lcr = line_control(config).ok_or_else(|| {
+            SalmiakErrorKind::InitSerialError(format!(
+                "The mini UART can not do {:?} data bits, {:?} parity and {:?} stop bits",
+                config.data_bits, config.parity, config.stop_bits
+            ))
+        })?;
+
+        let mut clock_rate = ClockRate { id: 0, hz: 0 };
+        let res = MailboxPropertyBufferBuilder::new()
+            .get_clock_rate(mailbox::clock::CORE, &mut clock_rate)
+            .submit();
+
+        if !res || clock_rate.hz == 0 {
+            return Err(SalmiakErrorKind::InitSerialError(
+                "Failed to get core clockrate".to_owned(),
+            )
+            .into());
+        }
+
+        let baud = baud_register(clock_rate.hz, config.baud).ok_or_else(|| {
+            SalmiakErrorKind::InitSerialError(format!(
+                "Baud rate {} is not possible with a {} Hz core clock",
+                config.baud, clock_rate.hz
+            ))
+        })?;
+
+        unsafe {
+            AUX_ENABLES.write_volatile(AUX_ENABLES.read_volatile() | AUX_ENABLE_MINI_UART);
+
+            // Disable the receiver, transmitter and interrupts while
+            // configuring
+            AUX_MU_CNTL.write_volatile(0);
+            AUX_MU_IER.write_volatile(0);
+            AUX_MU_LCR.write_volatile(lcr);
+            AUX_MU_MCR.write_volatile(0);
+            AUX_MU_IIR.write_volatile(IIR_CLEAR_FIFOS);
+            AUX_MU_BAUD.write_volatile(baud);
+        }
+
+        super::setup_pins(Function::Alt5);
+
+        unsafe {
+            AUX_MU_CNTL.write_volatile(CNTL_RX_TX);
+        }
+        Ok(())
+    }
+
+    fn readchar(&self) -> Option<u8> {
+        if receive_fifo_empty() {
+            return None;
+        }
+
+        Some(unsafe { AUX_MU_IO.read_volatile() as u8 })
+    }
+
+    fn flush(&self) {
+        while unsafe { AUX_MU_LSR.read_volatile() & LSR_TX_IDLE == 0 } {}
+    }
+
+    fn try_writechar(&self, c: u8) -> bool {
+        if transmit_fifo_full() {
+            return false;
+        }
+
+        unsafe {
+            AUX_MU_IO.write_volatile(u32::from(c));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baud_rates() {
+        // 250 MHz is the default core clock on the Pi 3
+        assert_eq!(baud_register(250_000_000, 115_200), Some(270));
+        assert_eq!(baud_register(400_000_000, 115_200), Some(433));
+        assert_eq!(baud_register(250_000_000, 0), None);
+        assert_eq!(baud_register(250_000_000, 100_000_000), None);
+        assert_eq!(baud_register(250_000_000, 300), None);
+    }
+
+    #[test]
+    fn supported_line_settings() {
+        assert_eq!(line_control(&SerialConfig::default()), Some(0b11));
+
+        let config = SerialConfig {
+            parity: Parity::Even,
+            ..SerialConfig::default()
+        };
+        assert_eq!(line_control(&config), None);
+    }
+}
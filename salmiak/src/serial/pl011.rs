@@ -0,0 +1,339 @@
+//! Driver for the PL011 UART at 0x3F201000.
+//!
+//! Polled until `enable_interrupts` is called, after that received and sent
+//! bytes go through ring buffers filled and drained by the UART interrupt.
+use super::{DataBits, Parity, SerialConfig, SerialPort, StopBits};
+use crate::cpu::{interrupt, irq};
+use crate::gpio::Function;
+use crate::gpu::mailbox::{self, ClockRate, MailboxPropertyBufferBuilder};
+use crate::prelude::*;
+use crate::ring_buffer::ByteRingBuffer;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const UART_DR: u32 = 0x3F20_1000;
+
+const UART0_DR: *mut u32 = UART_DR as *mut u32;
+// const UART0_RSRECR: u32 = (UART_DR + 0x04);
+const UART0_FR: *mut u32 = (UART_DR + 0x18) as *mut u32;
+// const UART0_ILPR: u32 = (UART_DR + 0x20);
+const UART0_IBRD: *mut u32 = (UART_DR + 0x24) as *mut u32;
+const UART0_FBRD: *mut u32 = (UART_DR + 0x28) as *mut u32;
+const UART0_LCRH: *mut u32 = (UART_DR + 0x2C) as *mut u32;
+const UART0_CR: *mut u32 = (UART_DR + 0x30) as *mut u32;
+const UART0_IFLS: *mut u32 = (UART_DR + 0x34) as *mut u32;
+const UART0_IMSC: *mut u32 = (UART_DR + 0x38) as *mut u32;
+// const UART0_RIS: u32 = (UART_DR + 0x3C);
+const UART0_MIS: *mut u32 = (UART_DR + 0x40) as *mut u32;
+const UART0_ICR: *mut u32 = (UART_DR + 0x44) as *mut u32;
+// const UART0_DMACR: u32 = (UART_DR + 0x48);
+// const UART0_ITCR: u32 = (UART_DR + 0x80);
+// const UART0_ITIP: u32 = (UART_DR + 0x84);
+// const UART0_ITOP: u32 = (UART_DR + 0x88);
+// const UART0_TDR: u32 = (UART_DR + 0x8C);
+
+const FR_BUSY: u32 = 1 << 3;
+
+// Interrupt bits of IMSC, MIS and ICR
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+const INT_RX_TIMEOUT: u32 = 1 << 6;
+
+/// GPU interrupt of the PL011.
+const UART_IRQ: usize = 57;
+
+// Line control bits
+const LCRH_PEN: u32 = 1 << 1;
+const LCRH_EPS: u32 = 1 << 2;
+const LCRH_STP2: u32 = 1 << 3;
+const LCRH_FEN: u32 = 1 << 4;
+
+/// Clock the UART is set to before computing the baud rate divisors.
+const UART_CLOCK: u32 = 4_000_000;
+
+static RX_BUFFER: ByteRingBuffer = ByteRingBuffer::new();
+static TX_BUFFER: ByteRingBuffer = ByteRingBuffer::new();
+static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+static RX_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Pl011;
+
+/// Value of the line control register for `config`, with FIFOs on.
+fn line_control(config: &SerialConfig) -> u32 {
+    let word_length = match config.data_bits {
+        DataBits::Five => 0b00,
+        DataBits::Six => 0b01,
+        DataBits::Seven => 0b10,
+        DataBits::Eight => 0b11,
+    };
+    let parity = match config.parity {
+        Parity::None => 0,
+        Parity::Odd => LCRH_PEN,
+        Parity::Even => LCRH_PEN | LCRH_EPS,
+    };
+    let stop_bits = match config.stop_bits {
+        StopBits::One => 0,
+        StopBits::Two => LCRH_STP2,
+    };
+
+    word_length << 5 | LCRH_FEN | parity | stop_bits
+}
+
+/// Integer and fractional baud rate divisors for `baud` with a UART clock of
+/// `clock` Hz, or `None` if they are out of range.
+///
+/// The divisor is `clock / (16 * baud)` with the fraction in 64ths, which
+/// rounded is `(4 * clock / baud + 0.5)`.
+fn baud_divisors(clock: u32, baud: u32) -> Option<(u32, u32)> {
+    if baud == 0 {
+        return None;
+    }
+
+    let divisor = (u64::from(clock) * 4 + u64::from(baud) / 2) / u64::from(baud);
+    let (integer, fraction) = ((divisor >> 6) as u32, (divisor & 0x3f) as u32);
+
+    match integer {
+        0 => None,
+        0xffff if fraction != 0 => None,
+        i if i > 0xffff => None,
+        _ => Some((integer, fraction)),
+    }
+}
+
+fn transmit_fifo_full() -> bool {
+    unsafe { UART0_FR.read_volatile() & (1 << 5) != 0 }
+}
+
+fn receive_fifo_empty() -> bool {
+    unsafe { UART0_FR.read_volatile() & (1 << 4) != 0 }
+}
+
+fn read_fifo() -> Option<u8> {
+    if receive_fifo_empty() {
+        return None;
+    }
+
+    // zeroes are data too, e.g. in a chainloaded kernel
+    Some(unsafe { UART0_DR.read_volatile() as u8 })
+}
+
+/// True when the interrupt handler is moving data between the FIFOs and the
+/// buffers. Exception handlers and critical sections have to do it themselves.
+fn interrupt_driven() -> bool {
+    INTERRUPTS_ENABLED.load(Ordering::Acquire) && !interrupt::irqs_masked()
+}
+
+/// Moves buffered output into the TX FIFO until either is full or empty.
+/// Has to be called with IRQs masked since it consumes `TX_BUFFER`.
+fn fill_transmit_fifo(_cs: &interrupt::CriticalSection) {
+    while !transmit_fifo_full() {
+        match unsafe { TX_BUFFER.pop() } {
+            Some(c) => unsafe { UART0_DR.write_volatile(u32::from(c)) },
+            None => break,
+        }
+    }
+
+    unsafe {
+        let imsc = UART0_IMSC.read_volatile();
+        if TX_BUFFER.is_empty() {
+            UART0_IMSC.write_volatile(imsc & !INT_TX);
+        } else {
+            UART0_IMSC.write_volatile(imsc | INT_TX);
+        }
+    }
+}
+
+/// Number of received bytes dropped because the RX buffer was full.
+pub fn dropped_bytes() -> usize {
+    RX_DROPPED.load(Ordering::Relaxed)
+}
+
+fn handle_interrupt() {
+    let status = unsafe { UART0_MIS.read_volatile() };
+
+    if status & (INT_RX | INT_RX_TIMEOUT) != 0 {
+        while let Some(c) = read_fifo() {
+            if !unsafe { RX_BUFFER.push(c) } {
+                RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    if status & INT_TX != 0 {
+        fill_transmit_fifo(&unsafe { interrupt::CriticalSection::new() });
+    }
+
+    unsafe {
+        UART0_ICR.write_volatile(status);
+    }
+}
+
+impl SerialPort for Pl011 {
+    fn init(&self, config: &SerialConfig) -> Result<(), SalmiakError> {
+        // Disable UART0
+        unsafe {
+            UART0_CR.write_volatile(0x0);
+        }
+
+        // we want consistent divisor values and
+        // therefore set the clock rate of the UART
+        let res = MailboxPropertyBufferBuilder::new()
+            .set_clock_rate(
+                mailbox::clock::UART,
+                UART_CLOCK,
+                0, // skip turbo
+                None,
+            )
+            .submit();
+
+        if !res {
+            return Err(SalmiakErrorKind::InitSerialError(
+                "Failed to set serial clockrate".to_owned(),
+            )
+            .into());
+        }
+
+        // the firmware may not give us exactly what we asked for
+        let mut clock_rate = ClockRate { id: 0, hz: 0 };
+        let res = MailboxPropertyBufferBuilder::new()
+            .get_clock_rate(mailbox::clock::UART, &mut clock_rate)
+            .submit();
+
+        if !res || clock_rate.hz == 0 {
+            return Err(SalmiakErrorKind::InitSerialError(
+                "Failed to get serial clockrate".to_owned(),
+            )
+            .into());
+        }
+
+        let (integer, fraction) = baud_divisors(clock_rate.hz, config.baud).ok_or_else(|| {
+            SalmiakErrorKind::InitSerialError(format!(
+                "Baud rate {} is not possible with a {} Hz UART clock",
+                config.baud, clock_rate.hz
+            ))
+        })?;
+
+        super::setup_pins(Function::Alt0);
+
+        unsafe {
+            // Clear pending interrupts.
+            UART0_ICR.write_volatile(0x7ff);
+
+            UART0_IBRD.write_volatile(integer);
+            UART0_FBRD.write_volatile(fraction);
+
+            // The divisors are latched by the write to LCRH
+            UART0_LCRH.write_volatile(line_control(config));
+
+            // Enable UART0, receive & transfer part of UART.
+            UART0_CR.write_volatile((1) | (1 << 8) | (1 << 9));
+        }
+        Ok(())
+    }
+
+    fn readchar(&self) -> Option<u8> {
+        // the interrupt handler is the only producer
+        if let Some(c) = interrupt::free(|_| unsafe { RX_BUFFER.pop() }) {
+            return Some(c);
+        }
+
+        if interrupt_driven() {
+            None
+        } else {
+            read_fifo()
+        }
+    }
+
+    fn try_writechar(&self, c: u8) -> bool {
+        if !interrupt_driven() {
+            if transmit_fifo_full() {
+                return false;
+            }
+            self.writechar(c);
+            return true;
+        }
+
+        interrupt::free(|cs| {
+            let queued = unsafe { TX_BUFFER.push(c) };
+            fill_transmit_fifo(cs);
+            queued
+        })
+    }
+
+    fn writechar(&self, c: u8) {
+        if interrupt_driven() {
+            while !self.try_writechar(c) {}
+            return;
+        }
+
+        // keep the order of anything that is still buffered
+        interrupt::free(|cs| {
+            while !TX_BUFFER.is_empty() {
+                fill_transmit_fifo(cs);
+            }
+        });
+
+        while transmit_fifo_full() {}
+        unsafe {
+            UART0_DR.write_volatile(u32::from(c));
+        }
+    }
+
+    fn flush(&self) {
+        interrupt::free(|cs| {
+            while !TX_BUFFER.is_empty() {
+                fill_transmit_fifo(cs);
+            }
+        });
+
+        while unsafe { UART0_FR.read_volatile() & FR_BUSY != 0 } {}
+    }
+
+    /// Moves received and sent bytes through ring buffers from the UART
+    /// interrupt instead of polling the FIFOs.
+    fn enable_interrupts(&self) {
+        unsafe {
+            // interrupt when the FIFOs are 1/8 full and 1/8 empty
+            UART0_IFLS.write_volatile(0);
+            UART0_ICR.write_volatile(0x7ff);
+            UART0_IMSC.write_volatile(INT_RX | INT_RX_TIMEOUT);
+        }
+
+        irq::register(UART_IRQ, handle_interrupt);
+        INTERRUPTS_ENABLED.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisors() {
+        // the values that used to be hard coded
+        assert_eq!(baud_divisors(4_000_000, 115_200), Some((2, 0xb)));
+        assert_eq!(baud_divisors(48_000_000, 115_200), Some((26, 3)));
+        assert_eq!(baud_divisors(3_000_000, 9600), Some((19, 34)));
+    }
+
+    #[test]
+    fn impossible_baud_rates() {
+        assert_eq!(baud_divisors(4_000_000, 0), None);
+        // needs a divisor below one
+        assert_eq!(baud_divisors(4_000_000, 1_000_000), None);
+        // needs a divisor above 0xffff
+        assert_eq!(baud_divisors(48_000_000, 40), None);
+    }
+
+    #[test]
+    fn line_control_bits() {
+        assert_eq!(line_control(&SerialConfig::default()), 0b111 << 4);
+
+        let config = SerialConfig {
+            baud: 9600,
+            data_bits: DataBits::Seven,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+        };
+        assert_eq!(line_control(&config), 0b10 << 5 | 0b1_1110);
+    }
+}
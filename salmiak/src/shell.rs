@@ -0,0 +1,760 @@
+//! Debug shell over the serial console.
+//!
+//! The shell only sees the input a game passes through `readchar`, which
+//! opens it on `OPEN_KEY` and hands every other byte back. While it is open
+//! lines are edited with backspace, ctrl-u and ctrl-c and run on enter, in
+//! between frames. Games add their own commands with `register`, e.g. to
+//! tweak variables live.
+use crate::chainload;
+use crate::cpu::interrupt::{self, Mutex};
+use crate::cpu::{exception, irq};
+use crate::dmesg::{self, Boot};
+use crate::gpu::{
+    self,
+    mailbox::{self, ARMMemory, ClockRate, MailboxPropertyBufferBuilder},
+    terminal::{self, ColorMode},
+};
+use crate::memory::{self, MB};
+use crate::power;
+use crate::serial::{self, SerialWriter};
+use core::cell::RefCell;
+use core::fmt::Write;
+
+/// Longest line that can be entered.
+pub const LINE_LENGTH: usize = 128;
+
+/// Most arguments a command can get.
+pub const MAX_ARGS: usize = 8;
+
+/// Number of commands games can register.
+pub const MAX_COMMANDS: usize = 16;
+
+/// Opens the shell when read by `readchar`.
+pub const OPEN_KEY: u8 = b':';
+
+/// Returned by commands given the wrong arguments to print their usage.
+pub const USAGE: &str = "usage";
+
+const PROMPT: &str = "> ";
+
+/// Most words `peek` prints.
+const MAX_PEEK: usize = 256;
+
+/// Returned by `peek` and `poke` for addresses that would fault.
+const UNMAPPED: &str = "the address is not RAM or a peripheral";
+
+pub type CommandFn = fn(args: &[&str]) -> Result<(), &'static str>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments, shown by `help` and on `USAGE` errors.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+}
+
+const BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "lists the commands",
+        run: help,
+    },
+    Command {
+        name: "exit",
+        usage: "",
+        help: "closes the shell",
+        run: exit,
+    },
+    Command {
+        name: "meminfo",
+        usage: "",
+        help: "heap usage",
+        run: meminfo,
+    },
+    Command {
+        name: "peek",
+        usage: "<addr> [words]",
+        help: "reads 32 bit words",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "<addr> <value>",
+        help: "writes a 32 bit word",
+        run: poke,
+    },
+    Command {
+        name: "chainload",
+        usage: "",
+        help: "boots a kernel sent with tools/chainload",
+        run: chainload,
+    },
+    Command {
+        name: "dmesg",
+        usage: "[prev]",
+        help: "kernel log of this or the last boot",
+        run: dmesg,
+    },
+    Command {
+        name: "irqstats",
+        usage: "",
+        help: "interrupt counts since boot",
+        run: irqstats,
+    },
+    Command {
+        name: "exctime",
+        usage: "[rounds]",
+        help: "time of an exception round trip",
+        run: exctime,
+    },
+    Command {
+        name: "mailbox",
+        usage: "memory|clocks",
+        help: "queries the firmware",
+        run: mailbox,
+    },
+    Command {
+        name: "reset",
+        usage: "",
+        help: "resets the board",
+        run: reset,
+    },
+    Command {
+        name: "screenshot",
+        usage: "",
+        help: "sends the screen as a binary PPM",
+        run: screenshot,
+    },
+    Command {
+        name: "term",
+        usage: "256|rgb|off [cols rows]",
+        help: "shows the screen in the terminal",
+        run: term,
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    Pending,
+    /// Enter was pressed, the line is in `LineEditor::line`.
+    Line,
+    /// The line was thrown away with ctrl-c.
+    Cancel,
+}
+
+/// Single line editor echoing to a terminal.
+pub struct LineEditor {
+    line: [u8; LINE_LENGTH],
+    len: usize,
+    after_cr: bool,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        LineEditor {
+            line: [0; LINE_LENGTH],
+            len: 0,
+            after_cr: false,
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        // only printable ASCII is added
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or("")
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Handles a received byte, echoing the changes to `echo`.
+    pub fn feed<W: Write>(&mut self, c: u8, echo: &mut W) -> Input {
+        let after_cr = self.after_cr;
+        self.after_cr = c == b'\r';
+
+        match c {
+            // terminals sending both end the line on the first
+            b'\n' if after_cr => Input::Pending,
+            b'\r' | b'\n' => {
+                let _ = echo.write_str("\r\n");
+                Input::Line
+            }
+            // ctrl-c
+            0x03 => {
+                let _ = echo.write_str("^C\r\n");
+                self.clear();
+                Input::Cancel
+            }
+            // backspace and delete
+            0x08 | 0x7f => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    let _ = echo.write_str("\x08 \x08");
+                }
+                Input::Pending
+            }
+            // ctrl-u
+            0x15 => {
+                for _ in 0..self.len {
+                    let _ = echo.write_str("\x08 \x08");
+                }
+                self.clear();
+                Input::Pending
+            }
+            0x20..=0x7e => {
+                if self.len < LINE_LENGTH {
+                    self.line[self.len] = c;
+                    self.len += 1;
+                    let _ = echo.write_char(c as char);
+                } else {
+                    let _ = echo.write_char('\x07');
+                }
+                Input::Pending
+            }
+            _ => Input::Pending,
+        }
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        LineEditor::new()
+    }
+}
+
+/// Commands added by the game.
+pub struct Registry {
+    commands: [Option<Command>; MAX_COMMANDS],
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Registry {
+            commands: [None; MAX_COMMANDS],
+        }
+    }
+
+    /// Adds `command`, replacing any with the same name. Returns false if
+    /// the registry is full.
+    pub fn register(&mut self, command: Command) -> bool {
+        let slot = self
+            .commands
+            .iter_mut()
+            .find(|c| c.map_or(true, |c| c.name == command.name));
+
+        match slot {
+            Some(slot) => {
+                *slot = Some(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<Command> {
+        self.iter().find(|c| c.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Command> + '_ {
+        self.commands.iter().filter_map(|c| *c)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+struct Shell {
+    open: bool,
+    editor: LineEditor,
+}
+
+static SHELL: Mutex<RefCell<Shell>> = Mutex::new(RefCell::new(Shell {
+    open: false,
+    editor: LineEditor::new(),
+}));
+
+static COMMANDS: Mutex<RefCell<Registry>> = Mutex::new(RefCell::new(Registry::new()));
+
+/// Adds a command to the shell, replacing any registered with the same name.
+/// Returns false if there are already `MAX_COMMANDS`.
+pub fn register(command: Command) -> bool {
+    interrupt::free(|cs| COMMANDS.borrow(cs).borrow_mut().register(command))
+}
+
+pub fn is_open() -> bool {
+    interrupt::free(|cs| SHELL.borrow(cs).borrow().open)
+}
+
+pub fn open() {
+    interrupt::free(|cs| {
+        let mut shell = SHELL.borrow(cs).borrow_mut();
+        shell.open = true;
+        shell.editor.clear();
+    });
+
+    sprintln!();
+    sprintln!("debug shell, 'help' lists the commands");
+    serial::write(PROMPT);
+}
+
+pub fn close() {
+    interrupt::free(|cs| SHELL.borrow(cs).borrow_mut().open = false);
+}
+
+/// Reads the serial console, passing everything to the shell while it is
+/// open. Returns the first byte not meant for the shell.
+pub fn readchar() -> Option<u8> {
+    while let Some(c) = serial::readchar() {
+        if is_open() {
+            feed(c);
+        } else if c == OPEN_KEY {
+            open();
+        } else {
+            return Some(c);
+        }
+    }
+
+    None
+}
+
+fn feed(c: u8) {
+    let mut line = [0; LINE_LENGTH];
+
+    let (input, len) = interrupt::free(|cs| {
+        let mut shell = SHELL.borrow(cs).borrow_mut();
+        let input = shell.editor.feed(c, &mut SerialWriter);
+        let len = shell.editor.line().len();
+        line[..len].copy_from_slice(shell.editor.line().as_bytes());
+        if input == Input::Line {
+            shell.editor.clear();
+        }
+        (input, len)
+    });
+
+    match input {
+        Input::Pending => return,
+        // commands run outside of the critical section
+        Input::Line => execute(core::str::from_utf8(&line[..len]).unwrap_or("")),
+        Input::Cancel => (),
+    }
+
+    if is_open() {
+        serial::write(PROMPT);
+    }
+}
+
+/// Splits `line` into words, returns `None` if there are too many.
+fn split<'a>(line: &'a str, words: &mut [&'a str; MAX_ARGS + 1]) -> Option<usize> {
+    let mut n = 0;
+    for word in line.split_whitespace() {
+        if n == words.len() {
+            return None;
+        }
+        words[n] = word;
+        n += 1;
+    }
+    Some(n)
+}
+
+fn find(name: &str) -> Option<Command> {
+    BUILTINS
+        .iter()
+        .copied()
+        .find(|c| c.name == name)
+        .or_else(|| interrupt::free(|cs| COMMANDS.borrow(cs).borrow().find(name)))
+}
+
+/// Runs a command line as if it was entered in the shell.
+pub fn execute(line: &str) {
+    let mut words = [""; MAX_ARGS + 1];
+    let n = match split(line, &mut words) {
+        Some(0) => return,
+        Some(n) => n,
+        None => {
+            sprintln!("too many arguments, at most {}", MAX_ARGS);
+            return;
+        }
+    };
+
+    let (name, args) = (words[0], &words[1..n]);
+    match find(name) {
+        Some(command) => match (command.run)(args) {
+            Ok(()) => (),
+            Err(USAGE) => sprintln!("usage: {} {}", name, command.usage),
+            Err(e) => sprintln!("{}: {}", name, e),
+        },
+        None => sprintln!("unknown command '{}', try 'help'", name),
+    }
+}
+
+/// Parses a hexadecimal number with a `0x` prefix or a decimal one.
+pub fn parse_number(s: &str) -> Option<usize> {
+    if s.starts_with("0x") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn print_command(command: &Command) {
+    let width = command.name.len() + 1 + command.usage.len();
+    sprintln!(
+        "  {} {}{:pad$} {}",
+        command.name,
+        command.usage,
+        "",
+        command.help,
+        pad = 24usize.saturating_sub(width)
+    );
+}
+
+fn help(_args: &[&str]) -> Result<(), &'static str> {
+    for command in BUILTINS {
+        print_command(command);
+    }
+    interrupt::free(|cs| {
+        for command in COMMANDS.borrow(cs).borrow().iter() {
+            print_command(&command);
+        }
+    });
+    Ok(())
+}
+
+fn exit(_args: &[&str]) -> Result<(), &'static str> {
+    close();
+    Ok(())
+}
+
+fn meminfo(_args: &[&str]) -> Result<(), &'static str> {
+    let heap = memory::heap_stats();
+    sprintln!(
+        "heap 0x{:08x}-0x{:08x}, {} KB used, {} KB free",
+        heap.start,
+        heap.start + heap.size,
+        heap.used / 1024,
+        (heap.size - heap.used) / 1024
+    );
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> Result<(), &'static str> {
+    let (addr, words) = match args {
+        [addr] => (parse_number(addr), Some(1)),
+        [addr, words] => (parse_number(addr), parse_number(words)),
+        _ => return Err(USAGE),
+    };
+    let (addr, words) = (addr.ok_or(USAGE)?, words.ok_or(USAGE)?.min(MAX_PEEK));
+    if addr % 4 != 0 {
+        return Err("the address has to be 4 byte aligned");
+    }
+    if !memory::is_mapped(addr, words * 4) {
+        return Err(UNMAPPED);
+    }
+
+    let mut writer = SerialWriter;
+    for row in (0..words).step_by(4) {
+        let _ = write!(writer, "0x{:08x}:", addr + row * 4);
+        for i in row..words.min(row + 4) {
+            let value = unsafe { ((addr + i * 4) as *const u32).read_volatile() };
+            let _ = write!(writer, " {:08x}", value);
+        }
+        sprintln!();
+    }
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), &'static str> {
+    let (addr, value) = match args {
+        [addr, value] => (
+            parse_number(addr).ok_or(USAGE)?,
+            parse_number(value).ok_or(USAGE)?,
+        ),
+        _ => return Err(USAGE),
+    };
+    if addr % 4 != 0 {
+        return Err("the address has to be 4 byte aligned");
+    }
+    if value > u32::max_value() as usize {
+        return Err("the value does not fit in 32 bits");
+    }
+    if !memory::is_mapped(addr, 4) {
+        return Err(UNMAPPED);
+    }
+
+    unsafe {
+        (addr as *mut u32).write_volatile(value as u32);
+    }
+    Ok(())
+}
+
+fn chainload(args: &[&str]) -> Result<(), &'static str> {
+    if !args.is_empty() {
+        return Err(USAGE);
+    }
+
+    sprintln!("waiting for a kernel, ctrl-c aborts");
+    match chainload::receive() {
+        Ok(image) => boot(image),
+        Err(e) => {
+            sprintln!("{}", e);
+            Err("no kernel received")
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn boot(image: chainload::Image) -> Result<(), &'static str> {
+    sprintln!("booting {} bytes", image.len());
+    image.boot()
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn boot(_image: chainload::Image) -> Result<(), &'static str> {
+    Err("can only boot on the Pi")
+}
+
+fn dmesg(args: &[&str]) -> Result<(), &'static str> {
+    let boot = match args {
+        [] => Boot::Current,
+        ["prev"] => Boot::Previous,
+        _ => return Err(USAGE),
+    };
+
+    // not through sprintln, that would add the log to itself
+    let _ = dmesg::write_to(boot, &mut SerialWriter);
+    Ok(())
+}
+
+fn irqstats(_args: &[&str]) -> Result<(), &'static str> {
+    sprintln!("local timer {:>10}", irq::timer_count());
+    for i in 0..irq::GPU_IRQS {
+        let (count, registered) = (irq::count(i), irq::is_registered(i));
+        if count > 0 || registered {
+            sprintln!(
+                "gpu {:>2}      {:>10}{}",
+                i,
+                count,
+                if registered { "" } else { " (no handler)" }
+            );
+        }
+    }
+    Ok(())
+}
+
+fn exctime(args: &[&str]) -> Result<(), &'static str> {
+    let rounds = match args {
+        [] => 10_000,
+        [rounds] => parse_number(rounds)
+            .filter(|&n| n > 0 && n <= u32::max_value() as usize)
+            .ok_or(USAGE)? as u32,
+        _ => return Err(USAGE),
+    };
+
+    let round_trip = exception::time_round_trip(rounds);
+    sprintln!(
+        "round trip {:?}, {:?} without saving the fp state, which takes {:?}",
+        round_trip.total,
+        round_trip.without_fp_state(),
+        round_trip.fp_state
+    );
+    Ok(())
+}
+
+fn mailbox(args: &[&str]) -> Result<(), &'static str> {
+    match args {
+        ["memory"] => {
+            let mut arm_memory = ARMMemory::default();
+            if !MailboxPropertyBufferBuilder::new()
+                .get_arm_memory(&mut arm_memory)
+                .submit()
+            {
+                return Err("request failed");
+            }
+            sprintln!(
+                "arm memory 0x{:08x}, {} MB",
+                arm_memory.base_address,
+                arm_memory.size / MB
+            );
+        }
+        ["clocks"] => {
+            let clocks = [
+                ("arm", mailbox::clock::ARM),
+                ("core", mailbox::clock::CORE),
+                ("uart", mailbox::clock::UART),
+            ];
+            for (name, id) in clocks.iter() {
+                let mut clock_rate = ClockRate::default();
+                if !MailboxPropertyBufferBuilder::new()
+                    .get_clock_rate(*id, &mut clock_rate)
+                    .submit()
+                {
+                    return Err("request failed");
+                }
+                sprintln!("{:<4} clock {:>10} Hz", name, clock_rate.hz);
+            }
+        }
+        _ => return Err(USAGE),
+    }
+    Ok(())
+}
+
+fn reset(_args: &[&str]) -> Result<(), &'static str> {
+    power::reset()
+}
+
+fn screenshot(args: &[&str]) -> Result<(), &'static str> {
+    if !args.is_empty() {
+        return Err(USAGE);
+    }
+    let screen = gpu::screen().ok_or("there is no frame buffer")?;
+
+    // the header line lets scripts capturing the output find the image
+    sprintln!("screenshot {}x{} follows", screen.width, screen.height);
+    let mut writer = SerialWriter;
+    let _ = write!(writer, "P6\n{} {}\n255\n", screen.width, screen.height);
+    for y in 0..screen.height {
+        for x in 0..screen.width {
+            let (red, green, blue) = screen.pixel(x, y).rgb();
+            serial::writechar(red);
+            serial::writechar(green);
+            serial::writechar(blue);
+        }
+    }
+    sprintln!();
+    Ok(())
+}
+
+fn term(args: &[&str]) -> Result<(), &'static str> {
+    let (mode, size) = match args {
+        ["off"] => {
+            terminal::disable();
+            return Ok(());
+        }
+        [mode] => (mode, None),
+        [mode, columns, rows] => (
+            mode,
+            Some((
+                parse_number(columns).ok_or(USAGE)?,
+                parse_number(rows).ok_or(USAGE)?,
+            )),
+        ),
+        _ => return Err(USAGE),
+    };
+    let mode = match *mode {
+        "256" => ColorMode::Palette256,
+        "rgb" => ColorMode::TrueColor,
+        _ => return Err(USAGE),
+    };
+    let (columns, rows) = size.unwrap_or((
+        terminal::DEFAULT_COLUMNS as usize,
+        terminal::DEFAULT_ROWS as usize,
+    ));
+    if columns == 0 || rows == 0 || columns > 400 || rows > 200 {
+        return Err("the size has to be between 1x1 and 400x200");
+    }
+    gpu::screen().ok_or("there is no frame buffer")?;
+
+    // the view is drawn by the game loop, the shell would only draw over it
+    close();
+    terminal::enable(mode, columns as u32, rows as u32);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop(_args: &[&str]) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn command(name: &'static str, help: &'static str) -> Command {
+        Command {
+            name,
+            usage: "",
+            help,
+            run: noop,
+        }
+    }
+
+    #[test]
+    fn line_editing() {
+        let mut editor = LineEditor::new();
+        let mut echo = String::new();
+
+        for c in b"peek 0x10\x7f\x7f80" {
+            assert_eq!(editor.feed(*c, &mut echo), Input::Pending);
+        }
+        assert_eq!(editor.line(), "peek 0x80");
+        assert_eq!(editor.feed(b'\r', &mut echo), Input::Line);
+        // the \n of a \r\n pair does not enter an empty line
+        assert_eq!(editor.feed(b'\n', &mut echo), Input::Pending);
+        assert_eq!(echo, "peek 0x10\x08 \x08\x08 \x0880\r\n");
+
+        editor.clear();
+        editor.feed(b'x', &mut echo);
+        editor.feed(0x15, &mut echo);
+        assert_eq!(editor.line(), "");
+        assert_eq!(editor.feed(0x03, &mut echo), Input::Cancel);
+    }
+
+    #[test]
+    fn full_line() {
+        let mut editor = LineEditor::new();
+        let mut echo = String::new();
+
+        for _ in 0..LINE_LENGTH + 1 {
+            editor.feed(b'a', &mut echo);
+        }
+        assert_eq!(editor.line().len(), LINE_LENGTH);
+        assert!(echo.ends_with('\x07'));
+    }
+
+    #[test]
+    fn split_words() {
+        let mut words = [""; MAX_ARGS + 1];
+        assert_eq!(split("  poke 0x100   7 ", &mut words), Some(3));
+        assert_eq!(words[..3], ["poke", "0x100", "7"]);
+        assert_eq!(split("a b c d e f g h i j", &mut words), None);
+
+        assert_eq!(parse_number("0x3f20"), Some(0x3f20));
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0xg"), None);
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = Registry::new();
+
+        assert!(registry.register(command("speed", "old")));
+        assert!(registry.register(command("speed", "new")));
+        assert_eq!(registry.iter().count(), 1);
+        assert_eq!(registry.find("speed").unwrap().help, "new");
+        assert!(registry.find("sped").is_none());
+
+        let names = [
+            "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o",
+        ];
+        for name in names.iter() {
+            assert!(registry.register(command(name, "")));
+        }
+        assert!(!registry.register(command("full", "")));
+        assert!(registry.register(command("speed", "replaced when full")));
+    }
+
+    #[test]
+    fn peek_and_poke_check_addresses() {
+        // would wrap around the address space
+        assert_eq!(peek(&["0xfffffffffffffffc", "2"]), Err(UNMAPPED));
+        assert_eq!(poke(&["0xfffffffffffffffc", "1"]), Err(UNMAPPED));
+
+        // past the peripherals
+        assert_eq!(peek(&["0x80000000"]), Err(UNMAPPED));
+        assert_eq!(poke(&["0x80000000", "1"]), Err(UNMAPPED));
+    }
+}
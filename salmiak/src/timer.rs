@@ -1,40 +1,427 @@
+mod instant;
+pub mod system;
+
+pub use self::instant::{duration_to_ticks_at, ticks_to_duration_at, Instant};
+use crate::cpu::interrupt::{self, Mutex};
+use core::cell::RefCell;
+pub use core::time::Duration;
 use cortex_a::regs::*;
 
-const INTERVAL: u64 = 20_000_000;
+/// Maximum number of timers that can be scheduled at the same time.
+pub const MAX_TIMERS: usize = 16;
+
+const CNTP_CTL_ENABLE: u32 = 1;
+
+static SCHEDULER: Mutex<RefCell<Scheduler>> = Mutex::new(RefCell::new(Scheduler::new()));
+
+pub type Callback = fn();
+
+/// Identifies a scheduled timer so that it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle(u32);
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    /// Zero for one-shot timers.
+    period: u64,
+    callback: Callback,
+    handle: TimerHandle,
+}
+
+/// Timers ordered by deadline in a fixed capacity binary min-heap.
+pub struct Scheduler {
+    heap: [Option<Timer>; MAX_TIMERS],
+    len: usize,
+    next_handle: u32,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Scheduler {
+            heap: [None; MAX_TIMERS],
+            len: 0,
+            next_handle: 0,
+        }
+    }
+
+    fn deadline(&self, i: usize) -> u64 {
+        self.heap[i].map_or(u64::max_value(), |t| t.deadline)
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.deadline(parent) <= self.deadline(i) {
+                break;
+            }
+            self.heap.swap(parent, i);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let mut smallest = i;
+            for child in &[2 * i + 1, 2 * i + 2] {
+                if *child < self.len && self.deadline(*child) < self.deadline(smallest) {
+                    smallest = *child;
+                }
+            }
+
+            if smallest == i {
+                break;
+            }
+            self.heap.swap(smallest, i);
+            i = smallest;
+        }
+    }
+
+    fn push(&mut self, timer: Timer) {
+        self.heap[self.len] = Some(timer);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+    }
+
+    fn remove(&mut self, i: usize) -> Option<Timer> {
+        let timer = self.heap[i].take();
+        self.len -= 1;
+        self.heap.swap(i, self.len);
+        if i < self.len {
+            self.sift_down(i);
+            self.sift_up(i);
+        }
+        timer
+    }
+
+    /// Schedules `callback` to run at tick `deadline` and then every `period`
+    /// ticks if `period` is non-zero. Returns `None` if all `MAX_TIMERS` are
+    /// in use.
+    pub fn schedule(
+        &mut self,
+        deadline: u64,
+        period: u64,
+        callback: Callback,
+    ) -> Option<TimerHandle> {
+        if self.len == MAX_TIMERS {
+            return None;
+        }
+
+        let handle = TimerHandle(self.next_handle);
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.push(Timer {
+            deadline,
+            period,
+            callback,
+            handle,
+        });
+
+        Some(handle)
+    }
+
+    /// Removes a timer. Returns false if it has already fired or been
+    /// cancelled.
+    pub fn cancel(&mut self, handle: TimerHandle) -> bool {
+        let index = self.heap[..self.len]
+            .iter()
+            .position(|t| t.map(|t| t.handle) == Some(handle));
+
+        match index {
+            Some(i) => self.remove(i).is_some(),
+            None => false,
+        }
+    }
+
+    /// The earliest deadline of all scheduled timers.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.heap[0].map(|t| t.deadline)
+    }
+
+    /// Takes the callback of the earliest timer if it has expired at tick
+    /// `now`. Periodic timers are rescheduled, skipping any periods that
+    /// have already been missed.
+    pub fn pop_expired(&mut self, now: u64) -> Option<Callback> {
+        match self.heap[0] {
+            Some(timer) if timer.deadline <= now => {
+                if timer.period == 0 {
+                    self.remove(0);
+                } else {
+                    let missed = (now - timer.deadline) / timer.period + 1;
+                    self.heap[0] = Some(Timer {
+                        deadline: timer.deadline + missed * timer.period,
+                        ..timer
+                    });
+                    self.sift_down(0);
+                }
+
+                Some(timer.callback)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
 
 pub fn get_ticks() -> u64 {
     CNTPCT_EL0.get()
 }
 
+/// Frequency of the system counter in Hz.
+pub fn frequency() -> u64 {
+    u64::from(CNTFRQ_EL0.get())
+}
+
+/// Milliseconds since reset, `None` if the counter frequency has not been set
+/// up by the firmware.
 pub fn get_ms() -> Option<u64> {
-    let frq = u64::from(CNTFRQ_EL0.get());
-    (CNTPCT_EL0.get() * 1000).checked_div(frq)
+    let frq = u128::from(frequency());
+    (u128::from(get_ticks()) * 1000)
+        .checked_div(frq)
+        .map(|ms| ms as u64)
 }
 
-pub fn setup_timer_interrupt() {
-    // set the next interrupt
-    #[cfg(target_arch = "aarch64")]
-    unsafe {
-        asm!("msr CNTP_TVAL_EL0, $0"
-         :
-         : "r"(INTERVAL)
-         :
-         : "volatile");
-    }
-    // TODO: should be CNTP_CVAL_EL0.set(cur);
+/// Returns `None` if the counter frequency has not been set up by the
+/// firmware.
+pub fn ticks_to_duration(ticks: u64) -> Option<Duration> {
+    ticks_to_duration_at(ticks, frequency())
+}
 
-    CNTP_CTL_EL0.set(0x1);
+/// Returns `None` if the duration does not fit in 64 bits of ticks.
+pub fn duration_to_ticks(duration: Duration) -> Option<u64> {
+    duration_to_ticks_at(duration, frequency())
 }
 
-pub fn handle_timer_interrupt() {
-    // set the next interrupt
-    #[cfg(target_arch = "aarch64")]
+#[cfg(target_arch = "aarch64")]
+fn set_compare_value(ticks: u64) {
+    // cortex-a does not have CNTP_CVAL_EL0
     unsafe {
-        asm!("msr CNTP_TVAL_EL0, $0"
+        asm!("msr CNTP_CVAL_EL0, $0"
              :
-             : "r"(INTERVAL)
+             : "r"(ticks)
              :
              : "volatile");
     }
-    // TODO: should be CNTP_CVAL_EL0.set(cur);
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn set_compare_value(_ticks: u64) {}
+
+/// Programs the physical timer to fire at `deadline`, or turns it off.
+fn program(deadline: Option<u64>) {
+    match deadline {
+        Some(deadline) => {
+            set_compare_value(deadline);
+            CNTP_CTL_EL0.set(CNTP_CTL_ENABLE);
+        }
+        None => CNTP_CTL_EL0.set(0),
+    }
+}
+
+fn schedule(delay: u64, period: u64, callback: Callback) -> Option<TimerHandle> {
+    schedule_at(get_ticks().saturating_add(delay), period, callback)
+}
+
+fn schedule_at(deadline: u64, period: u64, callback: Callback) -> Option<TimerHandle> {
+    interrupt::free(|cs| {
+        let mut scheduler = SCHEDULER.borrow(cs).borrow_mut();
+        let handle = scheduler.schedule(deadline, period, callback);
+        program(scheduler.next_deadline());
+        handle
+    })
+}
+
+/// Calls `callback` from the timer interrupt once `duration` has passed.
+/// Returns `None` if all `MAX_TIMERS` timers are in use.
+pub fn after(duration: Duration, callback: Callback) -> Option<TimerHandle> {
+    let delay = duration_to_ticks(duration).unwrap_or_else(u64::max_value);
+    schedule(delay, 0, callback)
+}
+
+/// Calls `callback` from the timer interrupt every `period`, starting one
+/// period from now. Returns `None` if all `MAX_TIMERS` timers are in use.
+pub fn every(period: Duration, callback: Callback) -> Option<TimerHandle> {
+    // a period of zero ticks would make it a one-shot timer
+    let period = duration_to_ticks(period)
+        .unwrap_or_else(u64::max_value)
+        .max(1);
+    schedule(period, period, callback)
+}
+
+/// Cancels a scheduled timer. Returns false if the timer already fired or
+/// was cancelled.
+pub fn cancel(handle: TimerHandle) -> bool {
+    interrupt::free(|cs| {
+        let mut scheduler = SCHEDULER.borrow(cs).borrow_mut();
+        let cancelled = scheduler.cancel(handle);
+        program(scheduler.next_deadline());
+        cancelled
+    })
+}
+
+/// Busy waits for at least `duration`.
+pub fn delay(duration: Duration) {
+    // one extra tick since the conversion rounds down
+    let ticks = duration_to_ticks(duration).unwrap_or_else(u64::max_value);
+    let end = get_ticks().saturating_add(ticks).saturating_add(1);
+
+    while get_ticks() < end {}
+}
+
+pub fn delay_us(us: u64) {
+    delay(Duration::from_micros(us));
+}
+
+pub fn delay_ms(ms: u64) {
+    delay(Duration::from_millis(ms));
+}
+
+#[cfg(target_arch = "aarch64")]
+fn wait_for_interrupt() {
+    unsafe {
+        asm!("wfi" :::: "volatile");
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn wait_for_interrupt() {}
+
+/// Parks the core until `deadline`. Other interrupts are handled while
+/// sleeping.
+pub fn sleep_until(deadline: Instant) {
+    fn wake_up() {}
+
+    // without a free timer nothing is guaranteed to wake the core up
+    match schedule_at(deadline.ticks(), 0, wake_up) {
+        Some(handle) => {
+            // The timer could fire between the check and the wfi and leave
+            // the core asleep, so both happen with interrupts masked. A
+            // pending interrupt still ends the wfi and is handled once the
+            // critical section unmasks it again.
+            loop {
+                let slept = interrupt::free(|_| {
+                    let early = Instant::now() < deadline;
+                    if early {
+                        wait_for_interrupt();
+                    }
+                    early
+                });
+                if !slept {
+                    break;
+                }
+            }
+            cancel(handle);
+        }
+        None => while Instant::now() < deadline {},
+    }
+}
+
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now()
+        .checked_add(duration)
+        .unwrap_or_else(|| Instant::from_ticks(u64::max_value()));
+    sleep_until(deadline);
+}
+
+pub fn setup_timer_interrupt() {
+    // nothing to wait for until a timer is scheduled
+    program(None);
+}
+
+pub fn handle_timer_interrupt() {
+    let now = get_ticks();
+
+    // the scheduler is not borrowed while running callbacks so they can
+    // schedule new timers
+    while let Some(callback) =
+        interrupt::free(|cs| SCHEDULER.borrow(cs).borrow_mut().pop_expired(now))
+    {
+        callback();
+    }
+
+    interrupt::free(|cs| program(SCHEDULER.borrow(cs).borrow().next_deadline()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a() {}
+    fn b() {}
+    fn c() {}
+
+    fn same(x: Option<Callback>, y: Callback) -> bool {
+        x.map(|x| x as usize) == Some(y as usize)
+    }
+
+    #[test]
+    fn expires_in_deadline_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(300, 0, c).unwrap();
+        scheduler.schedule(100, 0, a).unwrap();
+        scheduler.schedule(200, 0, b).unwrap();
+
+        assert_eq!(scheduler.next_deadline(), Some(100));
+        assert!(scheduler.pop_expired(99).is_none());
+        assert!(same(scheduler.pop_expired(250), a));
+        assert!(same(scheduler.pop_expired(250), b));
+        assert!(scheduler.pop_expired(250).is_none());
+        assert!(same(scheduler.pop_expired(300), c));
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn periodic_skips_missed_periods() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(100, 50, a).unwrap();
+
+        assert!(same(scheduler.pop_expired(100), a));
+        assert_eq!(scheduler.next_deadline(), Some(150));
+
+        // 160 and 210 were missed
+        assert!(same(scheduler.pop_expired(220), a));
+        assert_eq!(scheduler.next_deadline(), Some(250));
+        assert_eq!(scheduler.len(), 1);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut scheduler = Scheduler::new();
+        let first = scheduler.schedule(100, 0, a).unwrap();
+        let second = scheduler.schedule(200, 0, b).unwrap();
+        scheduler.schedule(300, 0, c).unwrap();
+
+        assert!(scheduler.cancel(first));
+        assert!(!scheduler.cancel(first));
+        assert_eq!(scheduler.next_deadline(), Some(200));
+
+        assert!(same(scheduler.pop_expired(200), b));
+        assert!(!scheduler.cancel(second));
+        assert_eq!(scheduler.len(), 1);
+    }
+
+    #[test]
+    fn full() {
+        let mut scheduler = Scheduler::new();
+        for i in 0..MAX_TIMERS {
+            assert!(scheduler.schedule(i as u64, 0, a).is_some());
+        }
+
+        assert!(scheduler.schedule(0, 0, a).is_none());
+        scheduler.pop_expired(0);
+        assert!(scheduler.schedule(0, 0, a).is_some());
+    }
 }
@@ -0,0 +1,237 @@
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Converts a number of ticks at `frequency` Hz to a duration, `None` if the
+/// frequency is zero.
+pub fn ticks_to_duration_at(ticks: u64, frequency: u64) -> Option<Duration> {
+    let secs = ticks.checked_div(frequency)?;
+    // the remainder is less than the frequency, so this fits in 128 bits and
+    // the result is less than a second
+    let nanos = u128::from(ticks % frequency) * u128::from(NANOS_PER_SEC) / u128::from(frequency);
+    Some(Duration::new(secs, nanos as u32))
+}
+
+/// Converts a duration to a number of ticks at `frequency` Hz, rounding down.
+/// Returns `None` if the result does not fit in 64 bits.
+pub fn duration_to_ticks_at(duration: Duration, frequency: u64) -> Option<u64> {
+    let ticks = duration.as_nanos().checked_mul(u128::from(frequency))? / u128::from(NANOS_PER_SEC);
+    if ticks > u128::from(u64::max_value()) {
+        None
+    } else {
+        Some(ticks as u64)
+    }
+}
+
+/// A point in time measured by the system counter, CNTPCT_EL0.
+///
+/// The counter starts at zero on reset and does not wrap within the lifetime
+/// of a device, so instants are monotonic and safe to compare.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant {
+            ticks: super::get_ticks(),
+        }
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Instant { ticks }
+    }
+
+    pub fn ticks(self) -> u64 {
+        self.ticks
+    }
+
+    /// Time since reset, zero if the counter frequency has not been set up
+    /// by the firmware.
+    pub fn since_boot(self) -> Duration {
+        super::ticks_to_duration(self.ticks).unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Time passed since this instant.
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    /// Time between `earlier` and this instant, zero if `earlier` is later or
+    /// the counter frequency has not been set up.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    pub fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        self.ticks
+            .checked_sub(earlier.ticks)
+            .and_then(super::ticks_to_duration)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        self.checked_add_at(duration, super::frequency())
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant> {
+        self.checked_sub_at(duration, super::frequency())
+    }
+
+    fn checked_add_at(self, duration: Duration, frequency: u64) -> Option<Instant> {
+        duration_to_ticks_at(duration, frequency)
+            .and_then(|ticks| self.ticks.checked_add(ticks))
+            .map(Instant::from_ticks)
+    }
+
+    fn checked_sub_at(self, duration: Duration, frequency: u64) -> Option<Instant> {
+        duration_to_ticks_at(duration, frequency)
+            .and_then(|ticks| self.ticks.checked_sub(ticks))
+            .map(Instant::from_ticks)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PI_FREQUENCY: u64 = 19_200_000;
+
+    #[test]
+    fn ticks_to_duration() {
+        assert_eq!(
+            ticks_to_duration_at(PI_FREQUENCY, PI_FREQUENCY),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            ticks_to_duration_at(PI_FREQUENCY / 1000, PI_FREQUENCY),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(
+            ticks_to_duration_at(1, 62_500_000),
+            Some(Duration::from_nanos(16))
+        );
+
+        // would overflow when multiplying the ticks by 1000 first
+        let max = ticks_to_duration_at(u64::max_value(), PI_FREQUENCY).unwrap();
+        assert_eq!(max.as_secs(), u64::max_value() / PI_FREQUENCY);
+
+        // the firmware did not set the frequency
+        assert_eq!(ticks_to_duration_at(PI_FREQUENCY, 0), None);
+
+        // the remainder times a billion does not fit in 64 bits
+        let frequency = u64::max_value();
+        assert_eq!(
+            ticks_to_duration_at(frequency - 1, frequency),
+            Some(Duration::from_nanos(999_999_999))
+        );
+    }
+
+    #[test]
+    fn duration_to_ticks() {
+        assert_eq!(
+            duration_to_ticks_at(Duration::from_millis(20), PI_FREQUENCY),
+            Some(384_000)
+        );
+        assert_eq!(
+            duration_to_ticks_at(Duration::from_nanos(10), PI_FREQUENCY),
+            Some(0)
+        );
+        assert_eq!(
+            duration_to_ticks_at(Duration::from_secs(u64::max_value()), PI_FREQUENCY),
+            None
+        );
+
+        // the nanoseconds times the frequency do not fit in 128 bits
+        assert_eq!(
+            duration_to_ticks_at(Duration::from_secs(u64::max_value()), u64::max_value()),
+            None
+        );
+        assert_eq!(
+            duration_to_ticks_at(Duration::from_nanos(1), u64::max_value()),
+            Some(u64::max_value() / NANOS_PER_SEC)
+        );
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let instant = Instant::from_ticks(PI_FREQUENCY);
+        let second = Duration::from_secs(1);
+
+        assert_eq!(
+            instant.checked_add_at(second, PI_FREQUENCY),
+            Some(Instant::from_ticks(2 * PI_FREQUENCY))
+        );
+        assert_eq!(
+            instant.checked_sub_at(second, PI_FREQUENCY),
+            Some(Instant::from_ticks(0))
+        );
+        assert_eq!(
+            instant.checked_sub_at(Duration::from_millis(1001), PI_FREQUENCY),
+            None
+        );
+        assert_eq!(
+            Instant::from_ticks(u64::max_value()).checked_add_at(second, PI_FREQUENCY),
+            None
+        );
+        // too many ticks for 64 bits
+        assert_eq!(
+            instant.checked_add_at(Duration::from_secs(u64::max_value()), PI_FREQUENCY),
+            None
+        );
+    }
+
+    #[test]
+    fn ordering() {
+        let earlier = Instant::from_ticks(100);
+        let later = Instant::from_ticks(200);
+
+        assert!(earlier < later);
+        assert_eq!(earlier.max(later), later);
+        assert_eq!(
+            earlier.checked_duration_since(later),
+            None,
+            "time does not go backwards"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Driver for the free running 1 MHz system timer of the BCM2835.
+//!
+//! The timer has four compare channels of which the GPU uses 0 and 2, leaving
+//! 1 and 3 to the ARM (BCM2835 ARM Peripherals, section 12).
+use crate::cpu::interrupt::{self, Mutex};
+use crate::cpu::irq;
+use crate::prelude::*;
+use core::cell::Cell;
+use core::ptr::{read_volatile, write_volatile};
+use core::time::Duration;
+
+const SYSTEM_TIMER_BASE: *mut RegisterBlock =
+    (mem_constants::MMIO_BASE + 0x3000) as *mut RegisterBlock;
+
+pub type Handler = fn();
+
+/// Layout of the system timer registers.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct RegisterBlock {
+    /// Control/status, bit n is set when channel n has matched. Writing a one
+    /// clears it.
+    pub cs: u32,
+    pub clo: u32,
+    pub chi: u32,
+    pub c: [u32; 4],
+}
+
+/// Compare channels available to the ARM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    C1,
+    C3,
+}
+
+impl Channel {
+    fn index(self) -> usize {
+        match self {
+            Channel::C1 => 1,
+            Channel::C3 => 3,
+        }
+    }
+
+    /// Each channel has its own GPU interrupt with the channel's number.
+    fn irq(self) -> usize {
+        self.index()
+    }
+
+    fn handler(self) -> &'static Mutex<Cell<Option<Handler>>> {
+        match self {
+            Channel::C1 => &C1_HANDLER,
+            Channel::C3 => &C3_HANDLER,
+        }
+    }
+}
+
+pub struct SystemTimer {
+    regs: *mut RegisterBlock,
+}
+
+// Only accessed with volatile reads and writes of single registers
+unsafe impl Sync for SystemTimer {}
+
+impl SystemTimer {
+    /// # Safety
+    ///
+    /// `regs` has to point to the system timer registers, or a
+    /// `RegisterBlock` in memory.
+    pub const unsafe fn new(regs: *mut RegisterBlock) -> Self {
+        SystemTimer { regs }
+    }
+
+    /// Microseconds since reset.
+    pub fn now_us(&self) -> u64 {
+        let regs = self.regs;
+        unsafe {
+            // the high word can change between reading the two halves
+            loop {
+                let hi = read_volatile(&(*regs).chi);
+                let lo = read_volatile(&(*regs).clo);
+                if read_volatile(&(*regs).chi) == hi {
+                    return u64::from(hi) << 32 | u64::from(lo);
+                }
+            }
+        }
+    }
+
+    /// Makes `channel` match when the low 32 bits of the counter equal
+    /// `value`.
+    pub fn set_compare(&self, channel: Channel, value: u32) {
+        unsafe {
+            write_volatile(&mut (*self.regs).c[channel.index()], value);
+        }
+    }
+
+    pub fn is_matched(&self, channel: Channel) -> bool {
+        unsafe { read_volatile(&(*self.regs).cs) & (1 << channel.index()) != 0 }
+    }
+
+    /// Clears the match flag, and with it the interrupt, of `channel`.
+    pub fn clear_match(&self, channel: Channel) {
+        unsafe {
+            write_volatile(&mut (*self.regs).cs, 1 << channel.index());
+        }
+    }
+
+    /// Makes `channel` match once `duration` has passed, clearing an earlier
+    /// match. Durations are limited to the 32 bit compare registers.
+    pub fn arm(&self, channel: Channel, duration: Duration) {
+        let us = duration.as_micros().min(u128::from(u32::max_value())) as u32;
+        self.clear_match(channel);
+        self.set_compare(channel, (self.now_us() as u32).wrapping_add(us));
+    }
+}
+
+static SYSTEM_TIMER: SystemTimer = unsafe { SystemTimer::new(SYSTEM_TIMER_BASE) };
+static C1_HANDLER: Mutex<Cell<Option<Handler>>> = Mutex::new(Cell::new(None));
+static C3_HANDLER: Mutex<Cell<Option<Handler>>> = Mutex::new(Cell::new(None));
+
+/// Microseconds since reset.
+pub fn now_us() -> u64 {
+    SYSTEM_TIMER.now_us()
+}
+
+/// Calls `handler` from the interrupt handler when `duration` has passed.
+/// Durations are limited to the 32 bit compare registers, a bit over 71
+/// minutes, and replace any earlier alarm on the channel.
+pub fn set_alarm(channel: Channel, duration: Duration, handler: Handler) {
+    interrupt::free(|cs| {
+        channel.handler().borrow(cs).set(Some(handler));
+        SYSTEM_TIMER.arm(channel, duration);
+    });
+
+    irq::register(channel.irq(), handle_interrupt);
+}
+
+/// Cancels the alarm of `channel` if it has not fired yet.
+pub fn cancel_alarm(channel: Channel) {
+    irq::unregister(channel.irq());
+
+    interrupt::free(|cs| {
+        channel.handler().borrow(cs).set(None);
+        SYSTEM_TIMER.clear_match(channel);
+    });
+}
+
+fn handle_interrupt() {
+    dispatch(&SYSTEM_TIMER);
+}
+
+/// Clears the matched channels of `timer` and calls their handlers once.
+fn dispatch(timer: &SystemTimer) {
+    for channel in &[Channel::C1, Channel::C3] {
+        if !timer.is_matched(*channel) {
+            continue;
+        }
+
+        timer.clear_match(*channel);
+        let handler = interrupt::free(|cs| channel.handler().borrow(cs).replace(None));
+        if let Some(handler) = handler {
+            handler();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A `RegisterBlock` in memory, only accessed through the raw pointer the
+    /// timer has as well.
+    struct Mock {
+        regs: *mut RegisterBlock,
+        timer: SystemTimer,
+    }
+
+    impl Mock {
+        fn new() -> Self {
+            let regs = Box::into_raw(Box::new(RegisterBlock::default()));
+            Mock {
+                regs,
+                timer: unsafe { SystemTimer::new(regs) },
+            }
+        }
+    }
+
+    impl Drop for Mock {
+        fn drop(&mut self) {
+            unsafe { drop(Box::from_raw(self.regs)) }
+        }
+    }
+
+    #[test]
+    fn now_us() {
+        let mock = Mock::new();
+        unsafe {
+            (*mock.regs).chi = 0x1;
+            (*mock.regs).clo = 0x8000_0000;
+        }
+
+        assert_eq!(mock.timer.now_us(), 0x1_8000_0000);
+    }
+
+    #[test]
+    fn compare_channels() {
+        let mock = Mock::new();
+        let timer = &mock.timer;
+
+        timer.set_compare(Channel::C1, 1000);
+        timer.set_compare(Channel::C3, 2000);
+        assert_eq!(unsafe { (*mock.regs).c }, [0, 1000, 0, 2000]);
+
+        unsafe { (*mock.regs).cs = 1 << 3 };
+        assert!(!timer.is_matched(Channel::C1));
+        assert!(timer.is_matched(Channel::C3));
+
+        // write one to clear
+        timer.clear_match(Channel::C1);
+        assert_eq!(unsafe { (*mock.regs).cs }, 1 << 1);
+    }
+
+    #[test]
+    fn alarms() {
+        let mock = Mock::new();
+        unsafe { (*mock.regs).clo = u32::max_value() - 99 };
+
+        // the compare value wraps like the low word of the counter
+        mock.timer.arm(Channel::C3, Duration::from_micros(1000));
+        assert_eq!(unsafe { (*mock.regs).c[3] }, 900);
+        assert_eq!(
+            unsafe { (*mock.regs).cs },
+            1 << 3,
+            "an earlier match is cleared"
+        );
+
+        mock.timer.arm(Channel::C1, Duration::from_secs(3 * 3600));
+        assert_eq!(unsafe { (*mock.regs).c[1] }, u32::max_value() - 100);
+    }
+
+    #[test]
+    fn dispatch_matched_channels() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn handler() {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mock = Mock::new();
+        interrupt::free(|cs| C3_HANDLER.borrow(cs).set(Some(handler)));
+
+        // only C1 matched, which has no handler
+        unsafe { (*mock.regs).cs = 1 << 1 };
+        dispatch(&mock.timer);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+
+        unsafe { (*mock.regs).cs = 1 << 3 };
+        dispatch(&mock.timer);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(unsafe { (*mock.regs).cs }, 1 << 3, "the match is cleared");
+
+        // alarms fire once
+        dispatch(&mock.timer);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,584 @@
+//! XMODEM-CRC and YMODEM file transfers.
+//!
+//! Runs over any `Transport`, normally `serial::ConsoleTransport`, e.g. to
+//! push levels or save files to a running Pi with `sz --ymodem` or from
+//! minicom. Only the CRC variants are supported. Received data goes to a
+//! `Destination`: a caller provided buffer (`Buffer`), the heap (`Vec<u8>`)
+//! or memory from an `Allocator` (`Allocated`).
+//!
+//! XMODEM pads the last block with `SUB` (0x1a), the padding is kept unless
+//! it does not fit in the destination. YMODEM sends the name and size of the
+//! file first, so the data is cut to the size.
+use crate::memory::{Allocator, Layout};
+use crate::prelude::*;
+use crate::serial::Transport;
+use crate::timer::Duration;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Asks for CRC-16 instead of the 8 bit checksum.
+const CRC_MODE: u8 = b'C';
+pub const SUB: u8 = 0x1a;
+
+const BLOCK_SIZE: usize = 128;
+const LONG_BLOCK_SIZE: usize = 1024;
+
+/// Wait between start requests.
+const START_TIMEOUT: Duration = Duration::from_secs(3);
+/// Wait for the next packet or a reply to one.
+const PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest pause within a packet.
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Silence that ends a garbled packet.
+const PURGE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Timeouts or bad packets in a row before giving up.
+const MAX_ERRORS: u32 = 10;
+
+/// Where received data goes.
+pub trait Destination {
+    /// Called with the size of a YMODEM file before its data, returns false
+    /// if it does not fit.
+    fn reserve(&mut self, _size: usize) -> bool {
+        true
+    }
+
+    /// Adds `data` after what has been written so far, returns false if it
+    /// does not fit.
+    fn write(&mut self, data: &[u8]) -> bool;
+}
+
+/// Receives into a caller provided buffer.
+pub struct Buffer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Buffer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Buffer { buf, len: 0 }
+    }
+
+    /// Number of bytes written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a> Destination for Buffer<'a> {
+    fn reserve(&mut self, size: usize) -> bool {
+        size <= self.buf.len() - self.len
+    }
+
+    /// XMODEM padding that does not fit is dropped.
+    fn write(&mut self, data: &[u8]) -> bool {
+        let fits = data.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + fits].copy_from_slice(&data[..fits]);
+        self.len += fits;
+        data[fits..].iter().all(|&b| b == SUB)
+    }
+}
+
+impl Destination for Vec<u8> {
+    fn reserve(&mut self, size: usize) -> bool {
+        Vec::reserve(self, size);
+        true
+    }
+
+    fn write(&mut self, data: &[u8]) -> bool {
+        self.extend_from_slice(data);
+        true
+    }
+}
+
+/// Receives a YMODEM file into memory from `allocator`, allocated when the
+/// size is known. XMODEM does not send the size, so it can not be used.
+pub struct Allocated<'a> {
+    allocator: &'a dyn Allocator,
+    ptr: *mut u8,
+    size: usize,
+    len: usize,
+}
+
+impl<'a> Allocated<'a> {
+    pub fn new(allocator: &'a dyn Allocator) -> Self {
+        Allocated {
+            allocator,
+            ptr: core::ptr::null_mut(),
+            size: 0,
+            len: 0,
+        }
+    }
+
+    /// The received file, it stays allocated.
+    pub fn into_slice(self) -> &'a mut [u8] {
+        if self.ptr.is_null() {
+            return &mut [];
+        }
+
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<'a> Destination for Allocated<'a> {
+    fn reserve(&mut self, size: usize) -> bool {
+        if !self.ptr.is_null() || size == 0 {
+            return size == 0;
+        }
+
+        self.ptr = self
+            .allocator
+            .alloc(unsafe { Layout::from_size_align_unchecked(size, 16) });
+        self.size = size;
+        !self.ptr.is_null()
+    }
+
+    fn write(&mut self, data: &[u8]) -> bool {
+        if self.len + data.len() > self.size {
+            return false;
+        }
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.len), data.len());
+        }
+        self.len += data.len();
+        true
+    }
+}
+
+/// Name and size of a YMODEM file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: Option<usize>,
+}
+
+/// CRC-16 with polynomial 0x1021 and no inversion, as used by XMODEM.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn error(message: &str) -> SalmiakError {
+    SalmiakErrorKind::TransferError(message.to_owned()).into()
+}
+
+/// Tells the other side to stop.
+fn cancel<T: Transport>(t: &mut T) {
+    t.write(&[CAN, CAN]);
+}
+
+enum Packet {
+    Block { number: u8, len: usize },
+    Eot,
+    Cancel,
+}
+
+/// Reads a packet into `buf`, returns `None` on a timeout or a garbled
+/// packet.
+fn read_packet<T: Transport>(
+    t: &mut T,
+    buf: &mut [u8; LONG_BLOCK_SIZE],
+    timeout: Duration,
+) -> Option<Packet> {
+    let len = match t.read(timeout)? {
+        SOH => BLOCK_SIZE,
+        STX => LONG_BLOCK_SIZE,
+        EOT => return Some(Packet::Eot),
+        CAN if t.read(BYTE_TIMEOUT) == Some(CAN) => return Some(Packet::Cancel),
+        _ => {
+            purge(t);
+            return None;
+        }
+    };
+
+    let mut header = [0; 2];
+    let mut crc = [0; 2];
+    let complete =
+        read_into(t, &mut header) && read_into(t, &mut buf[..len]) && read_into(t, &mut crc);
+
+    if !complete
+        || header[0] != !header[1]
+        || crc16(&buf[..len]) != u16::from(crc[0]) << 8 | u16::from(crc[1])
+    {
+        purge(t);
+        return None;
+    }
+
+    Some(Packet::Block {
+        number: header[0],
+        len,
+    })
+}
+
+fn read_into<T: Transport>(t: &mut T, buf: &mut [u8]) -> bool {
+    for b in buf.iter_mut() {
+        match t.read(BYTE_TIMEOUT) {
+            Some(c) => *b = c,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Skips the rest of a garbled packet.
+fn purge<T: Transport>(t: &mut T) {
+    while t.read(PURGE_TIMEOUT).is_some() {}
+}
+
+/// Receives data blocks starting with block 1 until EOT. Asks for the first
+/// block with `CRC_MODE`. Returns the number of bytes received.
+fn receive_data<T: Transport, D: Destination>(
+    t: &mut T,
+    dest: &mut D,
+    size: Option<usize>,
+) -> Result<usize, SalmiakError> {
+    let mut buf = [0; LONG_BLOCK_SIZE];
+    let mut expected = 1u8;
+    let mut received = 0;
+    let mut started = false;
+    let mut errors = 0;
+    let mut reply = CRC_MODE;
+
+    loop {
+        t.write(&[reply]);
+        let timeout = if started {
+            PACKET_TIMEOUT
+        } else {
+            START_TIMEOUT
+        };
+
+        match read_packet(t, &mut buf, timeout) {
+            Some(Packet::Block { number, len }) if number == expected => {
+                // YMODEM pads the last block too
+                let len = size.map_or(len, |size| len.min(size - received));
+                if !dest.write(&buf[..len]) {
+                    cancel(t);
+                    return Err(error("The file does not fit"));
+                }
+
+                received += len;
+                expected = expected.wrapping_add(1);
+                started = true;
+                errors = 0;
+                reply = ACK;
+            }
+            // our ACK got lost
+            Some(Packet::Block { number, .. }) if number == expected.wrapping_sub(1) => {
+                reply = ACK;
+            }
+            Some(Packet::Block { .. }) => {
+                cancel(t);
+                return Err(error("Blocks out of sequence"));
+            }
+            Some(Packet::Eot) if started || size == Some(0) => {
+                t.write(&[ACK]);
+                return Ok(received);
+            }
+            Some(Packet::Cancel) => return Err(error("Cancelled by the sender")),
+            Some(Packet::Eot) | None => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(t);
+                    return Err(error("Too many errors"));
+                }
+                reply = if started { NAK } else { CRC_MODE };
+            }
+        }
+    }
+}
+
+/// Receives a file with XMODEM-CRC, 128 byte and 1 KiB blocks. Returns the
+/// number of bytes received, including the padding of the last block.
+pub fn receive<T: Transport, D: Destination>(
+    t: &mut T,
+    dest: &mut D,
+) -> Result<usize, SalmiakError> {
+    receive_data(t, dest, None)
+}
+
+/// Parses block 0 of YMODEM, `None` if it ends the batch.
+fn parse_file_info(block: &[u8]) -> Result<Option<FileInfo>, SalmiakError> {
+    let mut fields = block.split(|&b| b == 0);
+    let name = fields.next().unwrap_or(&[]);
+    if name.is_empty() {
+        return Ok(None);
+    }
+    let name = core::str::from_utf8(name).map_err(|_| error("Bad file name"))?;
+
+    // the size is followed by optional fields separated by spaces
+    let size = fields
+        .next()
+        .and_then(|info| info.split(|&b| b == b' ').next())
+        .and_then(|size| core::str::from_utf8(size).ok())
+        .and_then(|size| size.parse().ok());
+
+    Ok(Some(FileInfo {
+        name: name.to_owned(),
+        size,
+    }))
+}
+
+/// Receives the next file of a YMODEM batch into `dest`. Returns `None` when
+/// the sender ends the batch, so keep calling it until then.
+pub fn receive_ymodem<T: Transport, D: Destination>(
+    t: &mut T,
+    dest: &mut D,
+) -> Result<Option<FileInfo>, SalmiakError> {
+    let mut buf = [0; LONG_BLOCK_SIZE];
+    let mut errors = 0;
+
+    let info = loop {
+        t.write(&[CRC_MODE]);
+        match read_packet(t, &mut buf, START_TIMEOUT) {
+            Some(Packet::Block { number: 0, len }) => {
+                t.write(&[ACK]);
+                break parse_file_info(&buf[..len])?;
+            }
+            Some(Packet::Cancel) => return Err(error("Cancelled by the sender")),
+            _ => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    cancel(t);
+                    return Err(error("No file"));
+                }
+            }
+        }
+    };
+
+    let info = match info {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+
+    if let Some(size) = info.size {
+        if !dest.reserve(size) {
+            cancel(t);
+            return Err(error("The file does not fit"));
+        }
+    }
+
+    receive_data(t, dest, info.size)?;
+    Ok(Some(info))
+}
+
+/// Waits for the receiver to ask for CRC mode.
+fn wait_for_start<T: Transport>(t: &mut T) -> Result<(), SalmiakError> {
+    for _ in 0..MAX_ERRORS {
+        match t.read(START_TIMEOUT) {
+            Some(CRC_MODE) => return Ok(()),
+            Some(CAN) => return Err(error("Cancelled by the receiver")),
+            // a NAK asks for the checksum variant
+            _ => {}
+        }
+    }
+    Err(error("The receiver did not start"))
+}
+
+/// Sends one block until it is acknowledged. `data` is padded with `pad`.
+fn send_block<T: Transport>(
+    t: &mut T,
+    number: u8,
+    data: &[u8],
+    block_size: usize,
+    pad: u8,
+) -> Result<(), SalmiakError> {
+    let mut block = [pad; LONG_BLOCK_SIZE];
+    block[..data.len()].copy_from_slice(data);
+    let block = &block[..block_size];
+    let crc = crc16(block);
+
+    for _ in 0..MAX_ERRORS {
+        let start = if block_size == BLOCK_SIZE { SOH } else { STX };
+        t.write(&[start, number, !number]);
+        t.write(block);
+        t.write(&[(crc >> 8) as u8, crc as u8]);
+
+        match t.read(PACKET_TIMEOUT) {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err(error("Cancelled by the receiver")),
+            _ => {}
+        }
+    }
+
+    cancel(t);
+    Err(error("Too many errors"))
+}
+
+fn send_data<T: Transport>(t: &mut T, data: &[u8], block_size: usize) -> Result<(), SalmiakError> {
+    for (i, chunk) in data.chunks(block_size).enumerate() {
+        send_block(t, (i + 1) as u8, chunk, block_size, SUB)?;
+    }
+
+    for _ in 0..MAX_ERRORS {
+        t.write(&[EOT]);
+        if t.read(PACKET_TIMEOUT) == Some(ACK) {
+            return Ok(());
+        }
+    }
+    Err(error("EOT was not acknowledged"))
+}
+
+/// Sends `data` with XMODEM-CRC in 128 byte blocks, the last one padded with
+/// `SUB`.
+pub fn send<T: Transport>(t: &mut T, data: &[u8]) -> Result<(), SalmiakError> {
+    wait_for_start(t)?;
+    send_data(t, data, BLOCK_SIZE)
+}
+
+/// Sends `data` as a YMODEM batch of one file called `name`.
+pub fn send_ymodem<T: Transport>(t: &mut T, name: &str, data: &[u8]) -> Result<(), SalmiakError> {
+    let size = format!("{}", data.len());
+    if name.is_empty() || name.len() + size.len() + 2 > BLOCK_SIZE {
+        return Err(error("Bad file name"));
+    }
+
+    let mut info = [0; BLOCK_SIZE];
+    info[..name.len()].copy_from_slice(name.as_bytes());
+    info[name.len() + 1..name.len() + 1 + size.len()].copy_from_slice(size.as_bytes());
+
+    wait_for_start(t)?;
+    send_block(t, 0, &info, BLOCK_SIZE, 0)?;
+    wait_for_start(t)?;
+    send_data(t, data, LONG_BLOCK_SIZE)?;
+
+    // an empty name ends the batch
+    wait_for_start(t)?;
+    send_block(t, 0, &[], BLOCK_SIZE, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+
+    /// One end of a serial line between two threads.
+    struct Loopback {
+        rx: Receiver<u8>,
+        tx: Sender<u8>,
+        /// Flips a bit of the byte written at this position.
+        corrupt: Option<usize>,
+        written: usize,
+    }
+
+    impl Transport for Loopback {
+        fn read(&mut self, timeout: Duration) -> Option<u8> {
+            self.rx.recv_timeout(timeout).ok()
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                let b = if self.corrupt == Some(self.written) {
+                    b ^ 1
+                } else {
+                    b
+                };
+                self.written += 1;
+                let _ = self.tx.send(b);
+            }
+        }
+    }
+
+    fn loopback(corrupt: Option<usize>) -> (Loopback, Loopback) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let end = |rx, tx, corrupt| Loopback {
+            rx,
+            tx,
+            corrupt,
+            written: 0,
+        };
+        (end(a_rx, a_tx, corrupt), end(b_rx, b_tx, None))
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn xmodem_into_buffer() {
+        let data = test_data(300);
+        let (mut sender, mut receiver) = loopback(None);
+
+        let sent = data.clone();
+        let handle = thread::spawn(move || send(&mut sender, &sent));
+
+        let mut buf = [0; 300];
+        let mut dest = Buffer::new(&mut buf);
+        assert_eq!(receive(&mut receiver, &mut dest).unwrap(), 384);
+        // the padding of the last block does not fit and is dropped
+        assert_eq!(dest.len(), 300);
+        handle.join().unwrap().unwrap();
+        assert_eq!(&buf[..], &data[..]);
+    }
+
+    #[test]
+    fn xmodem_does_not_overflow() {
+        let (mut sender, mut receiver) = loopback(None);
+        let handle = thread::spawn(move || send(&mut sender, &test_data(300)));
+
+        let mut buf = [0; 200];
+        assert!(receive(&mut receiver, &mut Buffer::new(&mut buf)).is_err());
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn ymodem_with_retransmission() {
+        let data = test_data(2500);
+        // garble a byte in the first data block
+        let (mut sender, mut receiver) = loopback(Some(BLOCK_SIZE + 5 + 100));
+
+        let sent = data.clone();
+        let handle = thread::spawn(move || send_ymodem(&mut sender, "level1.map", &sent));
+
+        let mut file = Vec::new();
+        let info = receive_ymodem(&mut receiver, &mut file).unwrap();
+        assert_eq!(
+            info,
+            Some(FileInfo {
+                name: "level1.map".to_owned(),
+                size: Some(2500),
+            })
+        );
+        assert_eq!(file, data);
+
+        assert_eq!(receive_ymodem(&mut receiver, &mut file).unwrap(), None);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn file_info() {
+        assert_eq!(
+            parse_file_info(b"save.bin\x0042 13725742 100644\x00\x00").unwrap(),
+            Some(FileInfo {
+                name: "save.bin".to_owned(),
+                size: Some(42),
+            })
+        );
+        assert_eq!(parse_file_info(&[0; 128]).unwrap(), None);
+    }
+}
@@ -7,51 +7,147 @@ extern crate salmiak;
 
 #[cfg(target_arch = "aarch64")]
 mod entry {
-    use salmiak::gpu;
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use salmiak::cpu::pmu::{self, Event};
+    use salmiak::game::{Game, GameLoop};
+    use salmiak::gpu::{self, Gpu};
+    use salmiak::input::{ActionMap, InputState, Source};
+    use salmiak::keyboard::Key;
     use salmiak::memory::{
         alloc::{BumpAllocator, *},
         MB,
     };
-    use salmiak::serial;
+    use salmiak::shell::{self, Command, USAGE};
+    use salmiak::timer::Duration;
 
     entry!(boot);
 
-    fn boot() -> ! {
-        sprintln!("----- S.N.E.K.A -----");
+    /// Pixels moved per key press, tweakable with the `speed` command.
+    static SPEED: AtomicU32 = AtomicU32::new(10);
 
-        let gpu_allocator: BumpAllocator = create_child_allocator(None, 2 * MB);
-        let gpu = gpu::init(640, 480, &gpu_allocator).unwrap();
+    fn speed(args: &[&str]) -> Result<(), &'static str> {
+        match args {
+            [] => sprintln!("speed {}", SPEED.load(Ordering::Relaxed)),
+            [speed] => SPEED.store(speed.parse().map_err(|_| USAGE)?, Ordering::Relaxed),
+            _ => return Err(USAGE),
+        }
+        Ok(())
+    }
 
-        let mut ypos = 150;
-        let mut xpos = 150;
-        let move_dt = 10;
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Action {
+        Left,
+        Right,
+        Up,
+        Down,
+    }
 
-        loop {
-            gpu.clear_screen(&gpu::Color::BLACK);
-            gpu.draw_rectangle(356, 300, 100, 20, &gpu::Color::BLUE);
+    fn actions() -> ActionMap<Action> {
+        ActionMap::new()
+            .bind(Source::key(Key::Char('a')), Action::Left)
+            .bind(Source::key(Key::Left), Action::Left)
+            .bind(Source::key(Key::Char('d')), Action::Right)
+            .bind(Source::key(Key::Right), Action::Right)
+            .bind(Source::key(Key::Char('w')), Action::Up)
+            .bind(Source::key(Key::Up), Action::Up)
+            .bind(Source::key(Key::Char('s')), Action::Down)
+            .bind(Source::key(Key::Down), Action::Down)
+    }
 
-            if let Some(c) = serial::readchar() {
-                match c as char {
-                    'a' => xpos -= move_dt,
-                    'd' => xpos += move_dt,
-                    'w' => ypos -= move_dt,
-                    's' => ypos += move_dt,
-                    'r' => salmiak::power::reset(),
-                    _ => (),
-                };
-            }
+    struct Sneka {
+        xpos: u32,
+        ypos: u32,
+        actions: ActionMap<Action>,
+        /// Moves pressed for since the last update.
+        moves: (i32, i32),
+    }
+
+    impl Game for Sneka {
+        fn input(&mut self, input: &InputState) {
+            let actions = &self.actions;
+            let pressed = |action| actions.just_pressed(input, action) as i32;
+
+            // frames without an update keep their moves for the next one
+            self.moves.0 += pressed(Action::Right) - pressed(Action::Left);
+            self.moves.1 += pressed(Action::Down) - pressed(Action::Up);
+        }
+
+        fn update(&mut self, _dt: Duration) {
+            let move_dt = SPEED.load(Ordering::Relaxed) as i32;
+            let (dx, dy) = self.moves;
+            self.moves = (0, 0);
+
+            self.xpos = (self.xpos as i32 + dx * move_dt) as u32;
+            self.ypos = (self.ypos as i32 + dy * move_dt) as u32;
+        }
+
+        fn render(&mut self, gpu: &mut Gpu, _alpha: f32) {
+            profile!("clear", { gpu.clear_screen(&gpu::Color::BLACK) });
+            gpu.draw_rectangle(356, 300, 100, 20, &gpu::Color::BLUE);
 
             //draw super snek
-            for i in 0..15 {
-                let x = xpos + i * 7;
-                let y = ypos + i * 7;
+            profile!("snek", {
+                for i in 0..15 {
+                    let x = self.xpos + i * 7;
+                    let y = self.ypos + i * 7;
+
+                    gpu.draw_circle_shaded(x, y, 10, &gpu::Color::RED, &gpu::Color::GREEN);
+                }
+            });
+
+            profile!("swap", { gpu.swap() });
+        }
+    }
+
+    fn boot() -> ! {
+        sprintln!("----- S.N.E.K.A -----");
 
-                gpu.draw_circle_shaded(x, y, 10, &gpu::Color::RED, &gpu::Color::GREEN);
+        #[cfg(feature = "gdb")]
+        {
+            salmiak::gdb::init();
+            salmiak::gdb::breakpoint();
+        }
+
+        #[cfg(feature = "chainload")]
+        {
+            sprintln!("waiting for a kernel, ctrl-c starts the game");
+            match salmiak::chainload::receive() {
+                Ok(image) => image.boot(),
+                Err(e) => sprintln!("{}", e),
             }
+        }
+
+        pmu::init(&[
+            Event::InstructionsRetired,
+            Event::L1DataCacheRefill,
+            Event::BranchMispredicted,
+        ]);
 
-            gpu.swap();
-            // do game stuff
+        shell::register(Command {
+            name: "speed",
+            usage: "[pixels]",
+            help: "shows or sets the snek speed",
+            run: speed,
+        });
+        sprintln!("press ':' for the debug shell");
+        if salmiak::dmesg::has_previous() {
+            sprintln!("the last boot left a log, ':dmesg prev' prints it");
         }
+
+        let gpu_allocator: BumpAllocator = create_child_allocator(None, 2 * MB);
+        let mut gpu = gpu::init(640, 480, &gpu_allocator).unwrap();
+
+        let mut sneka = Sneka {
+            xpos: 150,
+            ypos: 150,
+            actions: actions(),
+            moves: (0, 0),
+        };
+
+        // 60 updates per second
+        GameLoop::new(Duration::from_nanos(16_666_667))
+            .frame_cap(60)
+            .run(&mut sneka, &mut gpu)
     }
 }
 
@@ -0,0 +1,130 @@
+//! Sends a kernel image to a Pi waiting in `chainload::receive` and prints
+//! what comes back over serial afterwards.
+//!
+//! Usage: chainload <serial device> <kernel image>
+//!        chainload --pipe <base> <kernel image>
+//!
+//! Serial devices have to be set up first, e.g. with
+//! `stty -F /dev/ttyUSB0 115200 raw -echo`. `--pipe` writes to `<base>.in`
+//! and reads from `<base>.out`, the FIFOs QEMU uses for `-serial pipe:<base>`.
+use salmiak::chainload::{self, Header, CHECKSUM_ERROR, MAX_IMAGE_SIZE, OK, REQUEST, SIZE_ERROR};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+
+const USAGE: &str = "usage: chainload <serial device> | --pipe <base> <kernel image>";
+
+fn read_byte(input: &mut dyn Read) -> Result<u8, String> {
+    let mut byte = [0];
+    match input.read(&mut byte) {
+        Ok(0) => Err("the serial port was closed".to_owned()),
+        Ok(_) => Ok(byte[0]),
+        Err(e) => Err(format!("failed to read: {}", e)),
+    }
+}
+
+/// Prints everything up to the next request.
+fn wait_for_request(input: &mut dyn Read) -> Result<(), String> {
+    let mut stdout = io::stdout();
+    let mut matched = 0;
+    while matched < REQUEST.len() {
+        let c = read_byte(input)?;
+        if c == REQUEST[matched] {
+            matched += 1;
+        } else {
+            matched = 0;
+            let _ = stdout.write_all(&[c]);
+            let _ = stdout.flush();
+        }
+    }
+    Ok(())
+}
+
+/// Reads a two byte reply, skipping requests the Pi sent before it saw the
+/// header.
+fn read_reply(input: &mut dyn Read) -> Result<[u8; 2], String> {
+    let mut reply = [0; 2];
+    let mut i = 0;
+    while i < reply.len() {
+        let c = read_byte(input)?;
+        if c != REQUEST[0] {
+            reply[i] = c;
+            i += 1;
+        }
+    }
+    Ok(reply)
+}
+
+fn open(args: &[String]) -> Result<(Box<dyn Read>, File, String), String> {
+    let open_error = |path: &str, e: io::Error| format!("failed to open {}: {}", path, e);
+
+    match args {
+        [flag, base, image] if flag == "--pipe" => {
+            let (input, output) = (format!("{}.out", base), format!("{}.in", base));
+            let writer = OpenOptions::new()
+                .write(true)
+                .open(&output)
+                .map_err(|e| open_error(&output, e))?;
+            let reader = File::open(&input).map_err(|e| open_error(&input, e))?;
+            Ok((Box::new(reader), writer, image.clone()))
+        }
+        [device, image] => {
+            let port = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(device)
+                .map_err(|e| open_error(device, e))?;
+            let reader = port.try_clone().map_err(|e| open_error(device, e))?;
+            Ok((Box::new(reader), port, image.clone()))
+        }
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+fn main() -> Result<(), String> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (mut input, mut output, path) = open(&args)?;
+
+    let image = fs::read(&path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    if image.is_empty() || image.len() > MAX_IMAGE_SIZE {
+        return Err(format!(
+            "{} is {} bytes, it has to be between 1 and {}",
+            path,
+            image.len(),
+            MAX_IMAGE_SIZE
+        ));
+    }
+
+    eprintln!("chainload: waiting for the Pi to ask for a kernel");
+    wait_for_request(&mut input)?;
+
+    let header = Header {
+        size: image.len() as u32,
+        checksum: chainload::checksum(&image),
+    };
+    output
+        .write_all(&header.to_bytes())
+        .map_err(|e| format!("failed to write: {}", e))?;
+
+    match read_reply(&mut input)? {
+        OK => {}
+        SIZE_ERROR => return Err("the Pi rejected the size of the image".to_owned()),
+        reply => return Err(format!("unexpected reply {:?}", reply)),
+    }
+
+    eprintln!("chainload: sending {} bytes", image.len());
+    output
+        .write_all(&image)
+        .and_then(|_| output.flush())
+        .map_err(|e| format!("failed to write: {}", e))?;
+
+    match read_reply(&mut input)? {
+        OK => {}
+        CHECKSUM_ERROR => return Err("the image was corrupted on the way".to_owned()),
+        reply => return Err(format!("unexpected reply {:?}", reply)),
+    }
+    eprintln!("chainload: booting");
+
+    io::copy(&mut input, &mut io::stdout()).map_err(|e| format!("failed to read: {}", e))?;
+    Ok(())
+}
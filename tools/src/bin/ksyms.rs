@@ -0,0 +1,45 @@
+//! Embeds a symbol table into a linked kernel so that backtraces printed on
+//! the Pi contain function names.
+//!
+//! Usage: ksyms <kernel elf>
+use rustc_demangle::demangle;
+use salmiak::cpu::symbols::{self, Symbol};
+use salmiak_tools::elf::Elf;
+use std::{env, fs};
+
+fn main() -> Result<(), String> {
+    let path = env::args().nth(1).ok_or("usage: ksyms <kernel elf>")?;
+    let data = fs::read(&path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let mut elf = Elf::parse(data)?;
+
+    let mut functions = elf.function_symbols()?;
+    functions.sort_by_key(|f| f.address);
+    functions.dedup_by_key(|f| f.address);
+
+    // `{:#}` leaves out the hash suffix of Rust symbols
+    let names: Vec<String> = functions
+        .iter()
+        .map(|f| format!("{:#}", demangle(&f.name)))
+        .collect();
+    let symbols: Vec<Symbol> = functions
+        .iter()
+        .zip(&names)
+        .map(|(f, name)| Symbol {
+            address: f.address,
+            size: f.size as u32,
+            name,
+        })
+        .collect();
+
+    let table = symbols::encode(&symbols);
+    elf.write_section(".ksyms", &table)?;
+    fs::write(&path, elf.into_bytes()).map_err(|e| format!("failed to write {}: {}", path, e))?;
+
+    println!(
+        "ksyms: embedded {} symbols ({} of {} bytes)",
+        symbols.len(),
+        table.len(),
+        symbols::RESERVED_SIZE
+    );
+    Ok(())
+}
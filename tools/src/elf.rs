@@ -0,0 +1,278 @@
+//! Just enough ELF64 parsing to patch sections of the linked kernel.
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub address: u64,
+    pub offset: usize,
+    pub size: usize,
+    pub link: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FunctionSymbol {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+}
+
+pub struct Elf {
+    data: Vec<u8>,
+    sections: Vec<Section>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("read outside of file at 0x{:x}", offset))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(
+        data.get(offset..offset + 4)
+            .ok_or_else(|| format!("read outside of file at 0x{:x}", offset))?,
+    );
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(
+        data.get(offset..offset + 8)
+            .ok_or_else(|| format!("read outside of file at 0x{:x}", offset))?,
+    );
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<String, String> {
+    let bytes = data
+        .get(offset..)
+        .ok_or_else(|| format!("string outside of file at 0x{:x}", offset))?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+impl Elf {
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < 64 || &data[0..4] != b"\x7fELF" {
+            return Err("not an ELF file".to_owned());
+        }
+
+        if data[4] != 2 || data[5] != 1 {
+            return Err("only little endian ELF64 is supported".to_owned());
+        }
+
+        let shoff = read_u64(&data, 0x28)? as usize;
+        let shentsize = read_u16(&data, 0x3a)? as usize;
+        let shnum = read_u16(&data, 0x3c)? as usize;
+        let shstrndx = read_u16(&data, 0x3e)? as usize;
+
+        let mut sections = Vec::with_capacity(shnum);
+        let mut name_offsets = Vec::with_capacity(shnum);
+        for i in 0..shnum {
+            let header = shoff + i * shentsize;
+            name_offsets.push(read_u32(&data, header)? as usize);
+            sections.push(Section {
+                name: String::new(),
+                kind: read_u32(&data, header + 4)?,
+                address: read_u64(&data, header + 16)?,
+                offset: read_u64(&data, header + 24)? as usize,
+                size: read_u64(&data, header + 32)? as usize,
+                link: read_u32(&data, header + 40)?,
+            });
+        }
+
+        let names = sections
+            .get(shstrndx)
+            .ok_or("missing section name table")?
+            .offset;
+        for (section, name) in sections.iter_mut().zip(name_offsets) {
+            section.name = read_str(&data, names + name)?;
+        }
+
+        Ok(Elf { data, sections })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// All defined function symbols, unsorted and with mangled names.
+    pub fn function_symbols(&self) -> Result<Vec<FunctionSymbol>, String> {
+        let symtab = self
+            .sections
+            .iter()
+            .find(|s| s.kind == SHT_SYMTAB)
+            .ok_or("no symbol table, is the file stripped?")?;
+        let strtab = self
+            .sections
+            .get(symtab.link as usize)
+            .ok_or("missing symbol string table")?;
+
+        let mut symbols = Vec::new();
+        for i in 0..symtab.size / SYMBOL_SIZE {
+            let entry = symtab.offset + i * SYMBOL_SIZE;
+            let info = self.data[entry + 4];
+            let address = read_u64(&self.data, entry + 8)?;
+            if info & 0xf != STT_FUNC || address == 0 {
+                continue;
+            }
+
+            symbols.push(FunctionSymbol {
+                name: read_str(
+                    &self.data,
+                    strtab.offset + read_u32(&self.data, entry)? as usize,
+                )?,
+                address,
+                size: read_u64(&self.data, entry + 16)?,
+            });
+        }
+
+        Ok(symbols)
+    }
+
+    /// Overwrites the start of section `name` with `bytes`.
+    pub fn write_section(&mut self, name: &str, bytes: &[u8]) -> Result<(), String> {
+        let (offset, size) = self
+            .section(name)
+            .map(|s| (s.offset, s.size))
+            .ok_or_else(|| format!("no section named {}", name))?;
+
+        if bytes.len() > size {
+            return Err(format!(
+                "{} bytes do not fit in section {} of {} bytes",
+                bytes.len(),
+                name,
+                size
+            ));
+        }
+
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_section(
+        data: &mut Vec<u8>,
+        name: u32,
+        kind: u32,
+        offset: usize,
+        size: usize,
+        link: u32,
+    ) {
+        data.extend_from_slice(&name.to_le_bytes());
+        data.extend_from_slice(&kind.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes()); // flags
+        data.extend_from_slice(&0u64.to_le_bytes()); // address
+        data.extend_from_slice(&(offset as u64).to_le_bytes());
+        data.extend_from_slice(&(size as u64).to_le_bytes());
+        data.extend_from_slice(&link.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes()); // info
+        data.extend_from_slice(&8u64.to_le_bytes()); // alignment
+        data.extend_from_slice(&0u64.to_le_bytes()); // entry size
+    }
+
+    fn push_symbol(data: &mut Vec<u8>, name: u32, info: u8, address: u64, size: u64) {
+        data.extend_from_slice(&name.to_le_bytes());
+        data.push(info);
+        data.push(0);
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&address.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+    }
+
+    /// Section headers: null, .shstrtab, .ksyms, .symtab, .strtab
+    fn test_elf() -> Vec<u8> {
+        let shstrtab = b"\0.shstrtab\0.ksyms\0.symtab\0.strtab\0";
+        let strtab = b"\0_start\0data\0boot\0";
+
+        let mut body = Vec::new();
+        let shstrtab_offset = 64;
+        body.extend_from_slice(shstrtab);
+        let ksyms_offset = 64 + body.len();
+        body.extend_from_slice(&[0; 16]);
+        let strtab_offset = 64 + body.len();
+        body.extend_from_slice(strtab);
+        while body.len() % 8 != 0 {
+            body.push(0);
+        }
+        let symtab_offset = 64 + body.len();
+        push_symbol(&mut body, 0, 0, 0, 0);
+        push_symbol(&mut body, 1, STT_FUNC, 0x8_0000, 12);
+        push_symbol(&mut body, 8, 1, 0x9_0000, 4); // object
+        push_symbol(&mut body, 13, STT_FUNC | 0x10, 0x8_1000, 64);
+        let symtab_size = 64 + body.len() - symtab_offset;
+        let shoff = 64 + body.len();
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        data.resize(0x28, 0);
+        data.extend_from_slice(&(shoff as u64).to_le_bytes());
+        data.resize(0x3a, 0);
+        data.extend_from_slice(&64u16.to_le_bytes());
+        data.extend_from_slice(&5u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&body);
+
+        push_section(&mut data, 0, 0, 0, 0, 0);
+        push_section(&mut data, 1, 3, shstrtab_offset, shstrtab.len(), 0);
+        push_section(&mut data, 11, 1, ksyms_offset, 16, 0);
+        push_section(&mut data, 18, SHT_SYMTAB, symtab_offset, symtab_size, 4);
+        push_section(&mut data, 26, 3, strtab_offset, strtab.len(), 0);
+        data
+    }
+
+    #[test]
+    fn sections_and_symbols() {
+        let elf = Elf::parse(test_elf()).unwrap();
+
+        assert_eq!(elf.section(".ksyms").unwrap().size, 16);
+        assert!(elf.section(".text").is_none());
+        assert_eq!(
+            elf.function_symbols().unwrap(),
+            vec![
+                FunctionSymbol {
+                    name: "_start".to_owned(),
+                    address: 0x8_0000,
+                    size: 12,
+                },
+                FunctionSymbol {
+                    name: "boot".to_owned(),
+                    address: 0x8_1000,
+                    size: 64,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn write_section() {
+        let mut elf = Elf::parse(test_elf()).unwrap();
+        let offset = elf.section(".ksyms").unwrap().offset;
+
+        assert!(elf.write_section(".ksyms", &[1; 17]).is_err());
+        elf.write_section(".ksyms", &[1, 2, 3]).unwrap();
+
+        let data = elf.into_bytes();
+        assert_eq!(&data[offset..offset + 4], &[1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn not_elf() {
+        assert!(Elf::parse(vec![0; 128]).is_err());
+    }
+}
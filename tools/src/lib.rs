@@ -0,0 +1,3 @@
+//! Host side tools for working with salmiak kernels.
+
+pub mod elf;